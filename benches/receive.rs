@@ -0,0 +1,142 @@
+//! Measures how many packets per second the simulator executes and how much CPU it uses while idle.
+//!
+//! Run with `cargo bench --bench receive`. The simulator is started without a
+//! window using the `check` subcommand, so this also works on headless machines.
+
+use std::{
+    io::{BufRead, BufReader},
+    net::{SocketAddr, UdpSocket},
+    path::Path,
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+const SIMULATOR: &str = env!("CARGO_BIN_EXE_servicepoint-simulator");
+const SEND_DURATION: Duration = Duration::from_secs(3);
+const IDLE_WARMUP: Duration = Duration::from_secs(1);
+const IDLE_DURATION: Duration = Duration::from_secs(5);
+/// The unit of the CPU times in `/proc/<pid>/stat`, which is 100 on all common Linux systems.
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+fn main() {
+    let reference = std::env::temp_dir().join("servicepoint-bench.png");
+    println!(
+        "{:<24} {:>10} {:>10} {:>12}",
+        "packet", "sent", "executed", "executed/s"
+    );
+    for (name, packet) in [
+        ("16 pixel bitmap", bitmap_packet(2, 8)),
+        ("full screen bitmap", bitmap_packet(56, 160)),
+    ] {
+        let (sent, executed) = send_for(&reference, &packet, SEND_DURATION);
+        println!(
+            "{name:<24} {sent:>10} {executed:>10} {:>12.0}",
+            executed as f64 / SEND_DURATION.as_secs_f64()
+        );
+    }
+    match idle_cpu(&reference) {
+        Some(percent) => println!("idle CPU use: {percent:.2}%"),
+        None => println!("idle CPU use can only be measured on Linux"),
+    }
+    let _ = std::fs::remove_file(reference);
+}
+
+/// An uncompressed bitmap window command with all pixels on.
+fn bitmap_packet(tile_width: u16, pixel_height: u16) -> Vec<u8> {
+    let mut packet = Vec::new();
+    for field in [0x0013, 0, 0, tile_width, pixel_height] {
+        packet.extend_from_slice(&u16::to_be_bytes(field));
+    }
+    packet.resize(
+        packet.len() + tile_width as usize * pixel_height as usize,
+        0xff,
+    );
+    packet
+}
+
+/// Starts the simulator, returning the process and the address it receives packets on.
+fn start_simulator(reference: &Path, timeout: Duration) -> (Child, SocketAddr) {
+    let mut child = Command::new(SIMULATOR)
+        .arg("check")
+        .arg(reference)
+        .args([
+            "--update",
+            "--bind",
+            "127.0.0.1:0",
+            "--idle",
+            "500",
+            "--timeout",
+        ])
+        .arg(timeout.as_secs().to_string())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("could not start simulator");
+    let mut address = String::new();
+    BufReader::new(child.stdout.as_mut().unwrap())
+        .read_line(&mut address)
+        .expect("could not read address of simulator");
+    let address = address
+        .trim()
+        .parse()
+        .expect("simulator printed an invalid address");
+    (child, address)
+}
+
+/// Sends the packet as fast as possible and counts how many of them were executed.
+fn send_for(
+    reference: &Path,
+    packet: &[u8],
+    duration: Duration,
+) -> (usize, usize) {
+    let (child, address) = start_simulator(reference, duration * 10);
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let start = Instant::now();
+    let mut sent = 0;
+    while start.elapsed() < duration {
+        // packets the simulator cannot keep up with are dropped by the OS
+        if socket.send_to(packet, address).is_ok() {
+            sent += 1;
+        }
+    }
+
+    let output = child.wait_with_output().expect("simulator did not exit");
+    let log = String::from_utf8_lossy(&output.stderr);
+    let executed = log
+        .lines()
+        .find_map(|line| {
+            let (_, executed) = line.split_once("executed ")?;
+            executed.strip_suffix(" packets")?.parse().ok()
+        })
+        .expect("simulator did not log the executed packets");
+    (sent, executed)
+}
+
+/// The CPU time the simulator uses while waiting for packets, in percent of one core.
+fn idle_cpu(reference: &Path) -> Option<f64> {
+    let (mut child, _) =
+        start_simulator(reference, IDLE_WARMUP + IDLE_DURATION * 2);
+    std::thread::sleep(IDLE_WARMUP);
+    let before = cpu_ticks(child.id());
+    std::thread::sleep(IDLE_DURATION);
+    let after = cpu_ticks(child.id());
+    let _ = child.kill();
+    let _ = child.wait();
+
+    let ticks = after? - before?;
+    Some(
+        ticks as f64 / CLOCK_TICKS_PER_SECOND / IDLE_DURATION.as_secs_f64()
+            * 100.0,
+    )
+}
+
+/// User and system CPU time of a process in clock ticks.
+fn cpu_ticks(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // the process name may contain spaces, the other fields start after it
+    let (_, fields) = stat.rsplit_once(") ")?;
+    let fields = fields.split_whitespace().collect::<Vec<_>>();
+    let user = fields.get(11)?.parse::<u64>().ok()?;
+    let system = fields.get(12)?.parse::<u64>().ok()?;
+    Some(user + system)
+}
@@ -0,0 +1,382 @@
+use crate::{
+    cli::{self, CheckOptions},
+    firmware::Firmware,
+    front_buffer::FrontBuffer,
+    gui::AppEvents,
+    local_input::LocalInput,
+    metrics::METRICS,
+    packet_pipeline::PacketPipeline,
+    png::RgbImage,
+    sender_displays::ClientDisplays,
+    udp_server::{self, UdpServer},
+};
+use log::{error, info, warn};
+use servicepoint::{
+    Bitmap, Brightness, BrightnessGrid, Grid, PIXEL_HEIGHT, PIXEL_WIDTH,
+    TILE_SIZE,
+};
+use servicepoint_simulator::Simulator;
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    process::ExitCode,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+const MATCH_ON_COLOR: [u8; 3] = [0x60, 0x60, 0x60];
+const MATCH_OFF_COLOR: [u8; 3] = [0, 0, 0];
+const MISSING_COLOR: [u8; 3] = [0xff, 0, 0];
+const EXTRA_COLOR: [u8; 3] = [0, 0xff, 0];
+const BRIGHTNESS_COLOR: [u8; 3] = [0xff, 0xff, 0];
+
+#[derive(Debug, thiserror::Error)]
+enum CheckError {
+    #[error("could not access {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("could not read reference image: {0}")]
+    Png(#[from] ::png::DecodingError),
+    #[error(
+        "reference image is {0}x{1}, expected {PIXEL_WIDTH}x{PIXEL_HEIGHT}"
+    )]
+    WrongSize(usize, usize),
+}
+
+#[derive(Debug, Default)]
+struct Differences {
+    missing: usize,
+    extra: usize,
+    brightness: usize,
+}
+
+/// Receives packets without opening a window and compares the result to a reference image.
+///
+/// In the reference image, a pixel is on if any of its color channels is not zero,
+/// after drawing it onto black, so transparent pixels are off.
+/// When comparing brightness, the brightest channel of a pixel that is on
+/// encodes the brightness of its tile, scaled to the range 0 to 255.
+pub fn run(
+    mut options: CheckOptions,
+    firmware: Firmware,
+    simulator: Simulator,
+) -> ExitCode {
+    let front = FrontBuffer::new(
+        simulator.snapshot(),
+        options.defects.take().map(Arc::new),
+    );
+    let inputs = udp_server::bind_sockets(std::slice::from_ref(&options.bind))
+        .and_then(|sockets| {
+            Ok((sockets, LocalInput::open(&options.local_input)?))
+        });
+    let (sockets, local_input) = match inputs {
+        Ok(inputs) => inputs,
+        Err(err) => cli::exit_with_error(err),
+    };
+    let (events_tx, events_rx) = mpsc::channel();
+    let udp_server = UdpServer::new(
+        sockets,
+        &simulator,
+        &front,
+        ClientDisplays::Shared,
+        PacketPipeline::default(),
+        Box::new(events_tx),
+        firmware,
+    );
+    let udp_server = udp_server.and_then(|mut udp_server| {
+        udp_server.add_local_input(local_input)?;
+        Ok(udp_server)
+    });
+    let mut udp_server = match udp_server {
+        Ok(udp_server) => udp_server,
+        Err(err) => cli::exit_with_error(err),
+    };
+    let stop_udp = udp_server.stop_handle();
+
+    // the port may be chosen by the OS, so the caller needs to know where to send packets
+    println!("{}", udp_server.local_addr());
+    let _ = std::io::stdout().flush();
+
+    let packets = std::thread::scope(|scope| {
+        scope.spawn(move || udp_server.run());
+        let packets = wait_for_packets(&events_rx, &options);
+        stop_udp.stop();
+        packets
+    });
+    info!("executed {packets} packets");
+    info!("{}", METRICS.compression_report());
+
+    // compare what would be shown, which lags behind with a frame marker
+    let shown = front.read();
+    let result = if options.update {
+        update_reference(&options, shown.bitmap(), shown.brightness())
+    } else {
+        compare_to_reference(&options, shown.bitmap(), shown.brightness())
+    };
+    result.unwrap_or_else(|err| {
+        error!("{err}");
+        ExitCode::from(2)
+    })
+}
+
+fn wait_for_packets(
+    events_rx: &Receiver<AppEvents>,
+    options: &CheckOptions,
+) -> usize {
+    let deadline = Instant::now() + Duration::from_secs(options.timeout);
+    let idle = Duration::from_millis(options.idle);
+    let mut packets = 0;
+    loop {
+        let until_deadline = deadline.saturating_duration_since(Instant::now());
+        let wait = if packets > 0 {
+            until_deadline.min(idle)
+        } else {
+            until_deadline
+        };
+        match events_rx.recv_timeout(wait) {
+            Ok(AppEvents::UdpPacketsHandled(handled)) => {
+                packets += handled;
+                if options.packets.is_some_and(|expected| packets >= expected) {
+                    return packets;
+                }
+            }
+            Ok(AppEvents::UdpThreadClosed)
+            | Err(RecvTimeoutError::Disconnected) => return packets,
+            Err(RecvTimeoutError::Timeout) => {
+                if Instant::now() >= deadline {
+                    warn!("stopped waiting for packets after the timeout");
+                }
+                return packets;
+            }
+        }
+    }
+}
+
+fn update_reference(
+    options: &CheckOptions,
+    display: &Bitmap,
+    luma: &BrightnessGrid,
+) -> Result<ExitCode, CheckError> {
+    let image = reference_image(display, luma, options.brightness);
+    write_file(&options.reference, &image.encode())?;
+    info!("updated reference image {}", options.reference.display());
+    Ok(ExitCode::SUCCESS)
+}
+
+fn compare_to_reference(
+    options: &CheckOptions,
+    display: &Bitmap,
+    luma: &BrightnessGrid,
+) -> Result<ExitCode, CheckError> {
+    let reference = fs::read(&options.reference).map_err(|err| {
+        CheckError::Io(options.reference.display().to_string(), err)
+    })?;
+    let reference = RgbImage::decode(&reference)?;
+    if reference.width != PIXEL_WIDTH || reference.height != PIXEL_HEIGHT {
+        return Err(CheckError::WrongSize(reference.width, reference.height));
+    }
+
+    let (differences, diff) =
+        compare(&reference, display, luma, options.brightness);
+    if differences.total() == 0 {
+        info!("display matches {}", options.reference.display());
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let diff_path = options
+        .diff
+        .clone()
+        .unwrap_or_else(|| options.reference.with_extension("diff.png"));
+    write_file(&diff_path, &diff.encode())?;
+    error!(
+        "{} pixels differ from {} ({} missing, {} extra, {} with wrong brightness), see {}",
+        differences.total(),
+        options.reference.display(),
+        differences.missing,
+        differences.extra,
+        differences.brightness,
+        diff_path.display()
+    );
+    Ok(ExitCode::FAILURE)
+}
+
+/// The image that [compare] matches the display with.
+fn reference_image(
+    display: &Bitmap,
+    luma: &BrightnessGrid,
+    brightness: bool,
+) -> RgbImage {
+    let mut image = RgbImage::new(PIXEL_WIDTH, PIXEL_HEIGHT);
+    for y in 0..PIXEL_HEIGHT {
+        for x in 0..PIXEL_WIDTH {
+            if !display.get(x, y) {
+                continue;
+            }
+            let value = if brightness {
+                // still on at brightness 0, which 1 is rounded to when comparing
+                brightness_to_value(luma.get(x / TILE_SIZE, y / TILE_SIZE))
+                    .max(1)
+            } else {
+                u8::MAX
+            };
+            image.set(x, y, [value; 3]);
+        }
+    }
+    image
+}
+
+/// Counts the differing pixels and marks them in a diff image.
+fn compare(
+    reference: &RgbImage,
+    display: &Bitmap,
+    luma: &BrightnessGrid,
+    brightness: bool,
+) -> (Differences, RgbImage) {
+    let mut differences = Differences::default();
+    let mut diff = RgbImage::new(PIXEL_WIDTH, PIXEL_HEIGHT);
+    for y in 0..PIXEL_HEIGHT {
+        for x in 0..PIXEL_WIDTH {
+            let expected = reference.get(x, y).into_iter().max().unwrap();
+            let actual = luma.get(x / TILE_SIZE, y / TILE_SIZE);
+            let color = match (expected > 0, display.get(x, y)) {
+                (false, false) => MATCH_OFF_COLOR,
+                (true, false) => {
+                    differences.missing += 1;
+                    MISSING_COLOR
+                }
+                (false, true) => {
+                    differences.extra += 1;
+                    EXTRA_COLOR
+                }
+                (true, true)
+                    if brightness
+                        && value_to_brightness(expected) != actual =>
+                {
+                    differences.brightness += 1;
+                    BRIGHTNESS_COLOR
+                }
+                (true, true) => MATCH_ON_COLOR,
+            };
+            diff.set(x, y, color);
+        }
+    }
+    (differences, diff)
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), CheckError> {
+    fs::write(path, data)
+        .map_err(|err| CheckError::Io(path.display().to_string(), err))
+}
+
+fn brightness_to_value(brightness: Brightness) -> u8 {
+    let max = u8::from(Brightness::MAX) as u16;
+    ((u8::from(brightness) as u16 * u8::MAX as u16 + max / 2) / max) as u8
+}
+
+fn value_to_brightness(value: u8) -> Brightness {
+    let max = u8::from(Brightness::MAX) as u16;
+    let brightness = (value as u16 * max + u8::MAX as u16 / 2) / u8::MAX as u16;
+    Brightness::saturating_from(brightness as u8)
+}
+
+impl Differences {
+    fn total(&self) -> usize {
+        self.missing + self.extra + self.brightness
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use servicepoint::{TILE_HEIGHT, TILE_WIDTH};
+
+    fn display() -> (Bitmap, BrightnessGrid) {
+        let mut display = Bitmap::max_sized();
+        display.set(0, 0, true);
+        display.set(100, 50, true);
+        display.set(PIXEL_WIDTH - 1, PIXEL_HEIGHT - 1, true);
+        let mut luma = BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT);
+        luma.fill(Brightness::MAX);
+        luma.set(100 / TILE_SIZE, 50 / TILE_SIZE, Brightness::MIN);
+        (display, luma)
+    }
+
+    #[test]
+    fn brightness_round_trip() {
+        for value in u8::from(Brightness::MIN)..=u8::from(Brightness::MAX) {
+            let brightness = Brightness::saturating_from(value);
+            assert_eq!(
+                value_to_brightness(brightness_to_value(brightness)),
+                brightness
+            );
+        }
+    }
+
+    #[test]
+    fn brightness_rounding() {
+        assert_eq!(brightness_to_value(Brightness::MIN), 0);
+        assert_eq!(brightness_to_value(Brightness::MAX), u8::MAX);
+        // 255 / 11 = 23.18
+        assert_eq!(brightness_to_value(Brightness::saturating_from(1)), 23);
+        // 5 * 255 / 11 = 115.9
+        assert_eq!(brightness_to_value(Brightness::saturating_from(5)), 116);
+
+        assert_eq!(value_to_brightness(0), Brightness::MIN);
+        assert_eq!(value_to_brightness(u8::MAX), Brightness::MAX);
+        // 11 / 255 is the step size, half of it is rounded up
+        assert_eq!(value_to_brightness(11), Brightness::saturating_from(0));
+        assert_eq!(value_to_brightness(12), Brightness::saturating_from(1));
+    }
+
+    #[test]
+    fn updated_reference_matches() {
+        let (display, luma) = display();
+        for brightness in [false, true] {
+            let reference = reference_image(&display, &luma, brightness);
+            let reference = RgbImage::decode(&reference.encode()).unwrap();
+
+            let (differences, diff) =
+                compare(&reference, &display, &luma, brightness);
+
+            assert_eq!(differences.total(), 0);
+            assert_eq!(diff.get(0, 0), MATCH_ON_COLOR);
+            assert_eq!(diff.get(1, 0), MATCH_OFF_COLOR);
+        }
+    }
+
+    #[test]
+    fn compare_finds_differences() {
+        let (display, luma) = display();
+        let mut reference = reference_image(&display, &luma, true);
+        reference.set(0, 0, [0; 3]);
+        reference.set(1, 0, [0, 0, 0x10]);
+        reference.set(100, 50, [0xff; 3]);
+
+        let (differences, diff) = compare(&reference, &display, &luma, true);
+        assert_eq!(differences.missing, 1);
+        assert_eq!(differences.extra, 1);
+        assert_eq!(differences.brightness, 1);
+        assert_eq!(diff.get(0, 0), EXTRA_COLOR);
+        assert_eq!(diff.get(1, 0), MISSING_COLOR);
+        assert_eq!(diff.get(100, 50), BRIGHTNESS_COLOR);
+
+        // brightness is only compared when requested
+        let (differences, _) = compare(&reference, &display, &luma, false);
+        assert_eq!(differences.total(), 2);
+    }
+
+    #[test]
+    fn transparent_pixels_are_off() {
+        let (display, luma) = display();
+        let mut reference = reference_image(&display, &luma, false);
+        // what a transparent white pixel decodes to
+        reference.set(1, 0, [0; 3]);
+
+        let (differences, _) = compare(&reference, &display, &luma, false);
+
+        assert_eq!(differences.total(), 0);
+    }
+}
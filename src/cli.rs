@@ -1,19 +1,152 @@
-use clap::Parser;
+use crate::command_kind::CommandKind;
+use crate::compositor::{BlendMode, LayerRule};
+use crate::defects::{Defects, DefectsError};
+use crate::firmware::HardResetMode;
+use crate::hardware_emulation::CommandCost;
+use crate::packet_log::LogFormat;
+use crate::screensaver::ScreensaverKind;
+use crate::source_filter::Cidr;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
+use servicepoint_simulator::snapshot::{Snapshot, SnapshotError};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 #[derive(Parser, Debug)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     #[arg(
         long,
         default_value = "0.0.0.0:2342",
-        help = "address and port to bind to"
+        help = "address and port to bind to, can be given multiple times to listen on all of them, e.g. [::]:2342 for IPv6"
     )]
-    pub bind: String,
+    pub bind: Vec<String>,
+    #[clap(flatten)]
+    pub multicast: MulticastOptions,
+    #[clap(flatten)]
+    pub local_input: LocalInputOptions,
     #[arg(
         short,
         long,
         help = "The name of the font family to use. This defaults to the system monospace font."
     )]
     pub font: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser = load_snapshot,
+        help = "start with the display state from a snapshot file"
+    )]
+    pub initial_state: Option<Snapshot>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser = load_defects,
+        help = "simulate broken pixels and modules listed in this file"
+    )]
+    pub defects: Option<Defects>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "show a splash screen with the address to send packets to on startup and after a hard reset"
+    )]
+    pub boot_splash: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = HardResetMode::Exit,
+        help = "what to do when receiving a hard reset command"
+    )]
+    pub hard_reset: HardResetMode,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ClientMode::Shared,
+        help = "how packets from different senders are combined"
+    )]
+    pub clients: ClientMode,
+    #[arg(
+        long = "layer",
+        value_name = "SOURCE=PRIORITY[,BLEND]",
+        help = "configure the layer of the senders matching an address, IP or source port when compositing"
+    )]
+    pub layers: Vec<LayerRule>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = BlendMode::Or,
+        help = "blend mode for layers without one configured"
+    )]
+    pub blend: BlendMode,
+    #[arg(
+        long,
+        value_name = "SENDERS",
+        default_value_t = 64,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "how many senders get their own display with --clients isolated or composited, beyond that the one idle the longest is forgotten"
+    )]
+    pub max_senders: usize,
+    #[arg(
+        long,
+        value_name = "CIDR",
+        help = "only accept packets from these address ranges"
+    )]
+    pub allow: Vec<Cidr>,
+    #[arg(
+        long,
+        value_name = "CIDR",
+        help = "drop packets from these address ranges, even if they are allowed"
+    )]
+    pub deny: Vec<Cidr>,
+    #[arg(
+        long,
+        value_name = "PACKETS_PER_SECOND",
+        value_parser = parse_positive,
+        help = "limit the packets accepted per second from each source address"
+    )]
+    pub rate_limit: Option<f64>,
+    #[arg(
+        long,
+        value_name = "PACKETS",
+        requires = "rate_limit",
+        value_parser = parse_positive,
+        help = "how many packets a source can send at once before being rate limited [default: one second worth of packets]"
+    )]
+    pub rate_burst: Option<f64>,
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        help = "serve Prometheus metrics over HTTP on this address and port, e.g. 127.0.0.1:9100"
+    )]
+    pub metrics: Option<String>,
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        help = "serve a page showing the display in the browser on this address and port, e.g. 127.0.0.1:8080, not available with --clients isolated"
+    )]
+    pub web: Option<String>,
+    #[arg(
+        long,
+        requires = "web",
+        help = "execute packets that browsers send as binary WebSocket messages to the page of --web"
+    )]
+    pub web_input: bool,
+    #[arg(
+        long,
+        value_name = "ORIGIN",
+        requires = "web_input",
+        help = "also execute packets sent from pages on this origin, e.g. http://localhost:3000, can be repeated"
+    )]
+    pub web_origin: Vec<String>,
+    #[clap(flatten)]
+    pub network: NetworkOptions,
+    #[clap(flatten)]
+    pub hardware: HardwareOptions,
+    #[clap(flatten)]
+    pub screensaver: ScreensaverOptions,
     #[clap(flatten)]
     pub gui: GuiOptions,
     #[arg(
@@ -22,6 +155,268 @@ pub struct Cli {
         help = "Set default log level lower. You can also change this via the RUST_LOG environment variable."
     )]
     pub verbose: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = LogFormat::Text,
+        help = "how log lines are written, JSON adds an event for every packet"
+    )]
+    pub log_format: LogFormat,
+    #[arg(
+        long,
+        help = "explain invalid packets in detail, with the header fields and a hex dump"
+    )]
+    pub diagnostics: bool,
+    #[arg(
+        long,
+        help = "reject packets that deviate from the protocol even if they can be decoded, e.g. trailing bytes after compressed data or areas that do not fit on the display"
+    )]
+    pub strict: bool,
+    #[arg(
+        long,
+        help = "ignore the deprecated BitmapLegacy command like the real display does, instead of treating it as an error"
+    )]
+    pub legacy: bool,
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        value_enum,
+        help = "only show changes after a command of this kind, so frames sent as multiple packets are never shown in part"
+    )]
+    pub frame_marker: Option<CommandKind>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Receive packets without opening a window and compare the result to a reference image.
+    ///
+    /// Prints the address to send packets to, waits for packets and exits with
+    /// 0 if the display matches the reference image, 1 if it does not and 2 on errors.
+    Check(CheckOptions),
+}
+
+#[derive(Parser, Debug)]
+pub struct CheckOptions {
+    #[arg(help = "PNG image of the expected display content")]
+    pub reference: PathBuf,
+    #[arg(
+        long,
+        default_value = "127.0.0.1:0",
+        help = "address and port to bind to, port 0 lets the OS choose"
+    )]
+    pub bind: String,
+    #[clap(flatten)]
+    pub local_input: LocalInputOptions,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 10,
+        help = "stop waiting for packets after this time"
+    )]
+    pub timeout: u64,
+    #[arg(
+        long,
+        value_name = "MILLIS",
+        default_value_t = 1000,
+        help = "stop waiting when no packet arrived for this long after the first one"
+    )]
+    pub idle: u64,
+    #[arg(long, help = "stop waiting after this many packets were executed")]
+    pub packets: Option<usize>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "also compare the brightness of pixels that are on"
+    )]
+    pub brightness: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser = load_defects,
+        help = "simulate broken pixels and modules listed in this file"
+    )]
+    pub defects: Option<Defects>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "where to write the diff image on mismatch [default: <REFERENCE>.diff.png]"
+    )]
+    pub diff: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "write the received display content to the reference image instead of comparing"
+    )]
+    pub update: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientMode {
+    /// all senders draw onto the same display, like on the real one
+    Shared,
+    /// every sender gets its own virtual display, select one with Tab
+    Isolated,
+    /// every sender is a layer, the layers are combined into one display
+    Composited,
+}
+
+#[derive(Parser, Debug)]
+pub struct LocalInputOptions {
+    #[cfg(unix)]
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "also receive packets on a Unix datagram socket created at this path, they appear to be sent from 127.0.0.1:1, so --allow, --deny, --rate-limit and the display of sender 127.0.0.1 apply"
+    )]
+    pub unix: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "also read packets from stdin, each preceded by its length as a big-endian u16, they appear to be sent from 127.0.0.1:0, so --allow, --deny, --rate-limit and the display of sender 127.0.0.1 apply"
+    )]
+    pub stdin: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct MulticastOptions {
+    #[arg(
+        long = "multicast",
+        value_name = "GROUP",
+        value_parser = parse_multicast_group,
+        help = "join this IPv4 or IPv6 multicast group on the bound sockets of the same address family, can be given multiple times"
+    )]
+    pub groups: Vec<IpAddr>,
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        default_value_t = Ipv4Addr::UNSPECIFIED,
+        help = "address of the network interface to join IPv4 groups on, 0.0.0.0 lets the OS choose"
+    )]
+    pub multicast_interface: Ipv4Addr,
+    #[arg(
+        long,
+        value_name = "INDEX",
+        default_value_t = 0,
+        help = "index of the network interface to join IPv6 groups on, 0 lets the OS choose"
+    )]
+    pub multicast_interface_index: u32,
+}
+
+#[derive(Parser, Debug)]
+pub struct NetworkOptions {
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 0.0,
+        value_parser = parse_percent,
+        help = "randomly drop this percentage of received packets"
+    )]
+    pub loss: f64,
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 0.0,
+        value_parser = parse_percent,
+        help = "randomly duplicate this percentage of received packets"
+    )]
+    pub duplicate: f64,
+    #[arg(
+        long,
+        value_name = "PACKETS",
+        default_value_t = 0,
+        help = "randomly reorder packets within a window of this size"
+    )]
+    pub reorder: usize,
+    #[arg(
+        long,
+        value_name = "MILLIS",
+        default_value_t = 0,
+        help = "delay received packets by this amount"
+    )]
+    pub delay: u64,
+    #[arg(
+        long,
+        value_name = "MILLIS",
+        default_value_t = 0,
+        help = "additionally delay each packet by a random amount up to this value"
+    )]
+    pub jitter: u64,
+    #[arg(
+        long,
+        help = "seed for the network impairment, a random one is logged if not specified"
+    )]
+    pub seed: Option<u64>,
+}
+
+#[derive(Parser, Debug)]
+pub struct HardwareOptions {
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "process packets as slowly as the real display, dropping packets when it cannot keep up"
+    )]
+    pub emulate_hardware: bool,
+    #[arg(
+        long = "command-cost",
+        value_name = "COMMAND=MILLIS",
+        requires = "emulate_hardware",
+        help = "how long the emulated display is busy after a command of this kind"
+    )]
+    pub command_costs: Vec<CommandCost>,
+    #[arg(
+        long,
+        value_name = "MILLIS",
+        default_value_t = 0,
+        requires = "emulate_hardware",
+        help = "artificial delay before a received packet can be processed"
+    )]
+    pub latency: u64,
+    #[arg(
+        long,
+        value_name = "PACKETS",
+        default_value_t = 16,
+        requires = "emulate_hardware",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "how many packets can wait to be processed before new ones are dropped"
+    )]
+    pub queue_size: usize,
+}
+
+#[derive(Parser, Debug)]
+pub struct ScreensaverOptions {
+    #[arg(
+        long,
+        value_name = "MINUTES",
+        value_parser = parse_minutes,
+        help = "start a screensaver when no valid packet arrived for this long"
+    )]
+    pub idle_timeout: Option<Duration>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ScreensaverKind::Clock,
+        requires = "idle_timeout",
+        help = "what to show when idle"
+    )]
+    pub screensaver: ScreensaverKind,
+    #[arg(
+        long,
+        value_name = "TEXT",
+        default_value = "Service Point Display",
+        requires = "idle_timeout",
+        help = "text shown by the text screensaver, may contain multiple lines"
+    )]
+    pub screensaver_text: String,
+    #[arg(
+        long,
+        value_name = "OFFSET",
+        default_value = "+00:00",
+        value_parser = parse_utc_offset,
+        allow_hyphen_values = true,
+        requires = "idle_timeout",
+        help = "time zone of the clock screensaver as hours ahead of UTC, e.g. +02:00 or -5, daylight saving time is not followed"
+    )]
+    pub clock_utc_offset: i32,
 }
 
 #[derive(Parser, Debug)]
@@ -54,4 +449,176 @@ pub struct GuiOptions {
         help = "Use the blue color channel"
     )]
     pub blue: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "draw the tile grid boundaries (toggle with G)"
+    )]
+    pub grid: bool,
+    #[arg(
+        long,
+        value_name = "DIR",
+        default_value = ".",
+        help = "where snapshots of the shown display are saved (with S) and loaded from (with L)"
+    )]
+    pub snapshot_dir: PathBuf,
+    #[arg(
+        long,
+        value_name = "FPS",
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "redraw the window at most this many times per second, packets are executed in between"
+    )]
+    pub max_fps: u32,
+}
+
+fn load_defects(path: &str) -> Result<Defects, DefectsError> {
+    Defects::load(Path::new(path))
+}
+
+fn load_snapshot(path: &str) -> Result<Snapshot, SnapshotError> {
+    Snapshot::load(Path::new(path))
+}
+
+fn parse_multicast_group(value: &str) -> Result<IpAddr, String> {
+    let group = value.parse::<IpAddr>().map_err(|err| err.to_string())?;
+    if !group.is_multicast() {
+        return Err(format!("{group} is not a multicast address"));
+    }
+    Ok(group)
+}
+
+fn parse_minutes(value: &str) -> Result<Duration, String> {
+    let minutes = value.parse::<f64>().map_err(|err| err.to_string())?;
+    if minutes.is_nan() || minutes <= 0.0 {
+        return Err(format!("{value} is not a positive number of minutes"));
+    }
+    Duration::try_from_secs_f64(minutes * 60.0).map_err(|err| err.to_string())
+}
+
+/// Parses an offset from UTC like `+02:00`, `-5` or `5:30` into minutes.
+fn parse_utc_offset(value: &str) -> Result<i32, String> {
+    let invalid = || format!("{value} is not an offset like +02:00 or -5");
+    let (sign, offset) = match value.strip_prefix('-') {
+        Some(offset) => (-1, offset),
+        None => (1, value.strip_prefix('+').unwrap_or(value)),
+    };
+    // the integer parser would also accept another sign
+    if !offset.starts_with(|char: char| char.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+    let hours = hours.parse::<i32>().map_err(|_| invalid())?;
+    let minutes = minutes.parse::<i32>().map_err(|_| invalid())?;
+    if hours > 14 || !(0..60).contains(&minutes) {
+        return Err(invalid());
+    }
+    Ok(sign * (hours * 60 + minutes))
+}
+
+fn parse_positive(value: &str) -> Result<f64, String> {
+    let number = value.parse::<f64>().map_err(|err| err.to_string())?;
+    if !number.is_finite() || number <= 0.0 {
+        return Err(format!("{value} is not a positive number"));
+    }
+    Ok(number)
+}
+
+fn parse_percent(value: &str) -> Result<f64, String> {
+    let percent = value.parse::<f64>().map_err(|err| err.to_string())?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("{value} is not a percentage from 0 to 100"));
+    }
+    Ok(percent)
+}
+
+/// Exits because the simulator could not start, like clap does for invalid arguments,
+/// so the reason is shown even with logging turned off.
+pub fn exit_with_error(error: impl std::fmt::Display) -> ! {
+    Cli::command().error(ErrorKind::Io, error).exit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_multicast_group_valid() {
+        assert_eq!(
+            parse_multicast_group("239.255.0.1"),
+            Ok(IpAddr::V4(Ipv4Addr::new(239, 255, 0, 1)))
+        );
+        assert_eq!(
+            parse_multicast_group("ff02::1"),
+            Ok("ff02::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn parse_multicast_group_rejects_invalid() {
+        for value in ["10.0.0.1", "255.255.255.255", "::1", "fe80::1", "group"]
+        {
+            assert!(
+                parse_multicast_group(value).is_err(),
+                "{value} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_minutes_valid() {
+        assert_eq!(parse_minutes("2"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_minutes("0.5"), Ok(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn parse_minutes_rejects_invalid() {
+        for value in ["0", "-1", "nan", "inf", "1e300", "", "one"] {
+            assert!(parse_minutes(value).is_err(), "{value} was accepted");
+        }
+    }
+
+    #[test]
+    fn parse_utc_offset_valid() {
+        assert_eq!(parse_utc_offset("+00:00"), Ok(0));
+        assert_eq!(parse_utc_offset("+02:00"), Ok(120));
+        assert_eq!(parse_utc_offset("5:30"), Ok(330));
+        assert_eq!(parse_utc_offset("-5"), Ok(-300));
+        assert_eq!(parse_utc_offset("-09:30"), Ok(-570));
+    }
+
+    #[test]
+    fn parse_utc_offset_rejects_invalid() {
+        for value in ["", "+", "--5", "+-5", "15", "5:60", "5:-1", "5:", "utc"]
+        {
+            assert!(parse_utc_offset(value).is_err(), "{value} was accepted");
+        }
+    }
+
+    #[test]
+    fn parse_positive_valid() {
+        assert_eq!(parse_positive("2"), Ok(2.0));
+        assert_eq!(parse_positive("0.25"), Ok(0.25));
+    }
+
+    #[test]
+    fn parse_positive_rejects_invalid() {
+        for value in ["0", "-0", "-1", "nan", "inf", "-inf", "", "one"] {
+            assert!(parse_positive(value).is_err(), "{value} was accepted");
+        }
+    }
+
+    #[test]
+    fn parse_percent_valid() {
+        assert_eq!(parse_percent("0"), Ok(0.0));
+        assert_eq!(parse_percent("12.5"), Ok(12.5));
+        assert_eq!(parse_percent("100"), Ok(100.0));
+    }
+
+    #[test]
+    fn parse_percent_rejects_invalid() {
+        for value in ["-1", "100.1", "nan", "inf", "", "ten"] {
+            assert!(parse_percent(value).is_err(), "{value} was accepted");
+        }
+    }
 }
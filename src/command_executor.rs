@@ -2,6 +2,7 @@ use crate::{
     command_executor::ExecutionResult::{Failure, Shutdown, Success},
     cp437_font::Cp437Font,
     font_renderer::FontRenderer8x8,
+    snapshot::Snapshot,
 };
 use log::{debug, error, info, trace, warn};
 use servicepoint::{
@@ -19,11 +20,12 @@ use std::{
 pub struct CommandExecutionContext<'t> {
     display: &'t RwLock<Bitmap>,
     luma: &'t RwLock<BrightnessGrid>,
-    cp437_font: Cp437Font,
-    font_renderer: FontRenderer8x8,
+    cp437_font: &'t Cp437Font,
+    font_renderer: &'t FontRenderer8x8,
 }
 
 #[must_use]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ExecutionResult {
     Success,
     Failure,
@@ -250,13 +252,30 @@ impl<'t> CommandExecutionContext<'t> {
     pub fn new(
         display: &'t RwLock<Bitmap>,
         luma: &'t RwLock<BrightnessGrid>,
-        font_renderer: FontRenderer8x8,
+        cp437_font: &'t Cp437Font,
+        font_renderer: &'t FontRenderer8x8,
     ) -> Self {
         CommandExecutionContext {
             display,
             luma,
             font_renderer,
-            cp437_font: Cp437Font::default(),
+            cp437_font,
         }
     }
+
+    /// Copies the current state of the display this context draws onto.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(
+            self.display.read().unwrap().clone(),
+            self.luma.read().unwrap().clone(),
+        )
+        .expect("the display has the size of the real one")
+    }
+
+    /// Replaces the state of the display this context draws onto.
+    pub fn restore(&self, snapshot: Snapshot) {
+        let (bitmap, brightness) = snapshot.into_parts();
+        *self.display.write().unwrap() = bitmap;
+        *self.luma.write().unwrap() = brightness;
+    }
 }
@@ -0,0 +1,81 @@
+use clap::ValueEnum;
+use servicepoint::{CommandCode, CompressionCode, Header, TypedCommand};
+use std::fmt::{Display, Formatter};
+
+/// The variant of a [TypedCommand], without any of its data.
+#[derive(
+    ValueEnum, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum CommandKind {
+    Clear,
+    CharGrid,
+    Cp437Grid,
+    Bitmap,
+    Brightness,
+    BrightnessGrid,
+    BitVec,
+    HardReset,
+    FadeOut,
+    BitmapLegacy,
+}
+
+impl CommandKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandKind::Clear => "clear",
+            CommandKind::CharGrid => "char-grid",
+            CommandKind::Cp437Grid => "cp437-grid",
+            CommandKind::Bitmap => "bitmap",
+            CommandKind::Brightness => "brightness",
+            CommandKind::BrightnessGrid => "brightness-grid",
+            CommandKind::BitVec => "bit-vec",
+            CommandKind::HardReset => "hard-reset",
+            CommandKind::FadeOut => "fade-out",
+            CommandKind::BitmapLegacy => "bitmap-legacy",
+        }
+    }
+}
+
+impl From<&TypedCommand> for CommandKind {
+    fn from(command: &TypedCommand) -> Self {
+        match command {
+            TypedCommand::Clear(_) => CommandKind::Clear,
+            TypedCommand::CharGrid(_) => CommandKind::CharGrid,
+            TypedCommand::Cp437Grid(_) => CommandKind::Cp437Grid,
+            TypedCommand::Bitmap(_) => CommandKind::Bitmap,
+            TypedCommand::Brightness(_) => CommandKind::Brightness,
+            TypedCommand::BrightnessGrid(_) => CommandKind::BrightnessGrid,
+            TypedCommand::BitVec(_) => CommandKind::BitVec,
+            TypedCommand::HardReset(_) => CommandKind::HardReset,
+            TypedCommand::FadeOut(_) => CommandKind::FadeOut,
+            #[allow(deprecated)]
+            TypedCommand::BitmapLegacy(_) => CommandKind::BitmapLegacy,
+        }
+    }
+}
+
+/// The compression of a packet, if its command supports compression.
+pub fn compression_of(header: &Header) -> Option<CompressionCode> {
+    match CommandCode::try_from(header.command_code).ok()? {
+        CommandCode::BitmapLinearWinUncompressed => {
+            Some(CompressionCode::Uncompressed)
+        }
+        CommandCode::BitmapLinearWinZlib => Some(CompressionCode::Zlib),
+        CommandCode::BitmapLinearWinBzip2 => Some(CompressionCode::Bzip2),
+        CommandCode::BitmapLinearWinLzma => Some(CompressionCode::Lzma),
+        CommandCode::BitmapLinearWinZstd => Some(CompressionCode::Zstd),
+        CommandCode::BitmapLinear
+        | CommandCode::BitmapLinearAnd
+        | CommandCode::BitmapLinearOr
+        | CommandCode::BitmapLinearXor => {
+            CompressionCode::try_from(header.c).ok()
+        }
+        _ => None,
+    }
+}
+
+impl Display for CommandKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
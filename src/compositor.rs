@@ -0,0 +1,436 @@
+use crate::sender_displays::SenderDisplays;
+use clap::ValueEnum;
+use servicepoint::{
+    Bitmap, Brightness, BrightnessGrid, DataRef, Grid, TILE_HEIGHT, TILE_SIZE,
+    TILE_WIDTH,
+};
+use servicepoint_simulator::{snapshot::Snapshot, Simulator};
+use std::{
+    cell::Cell,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// How a layer is combined with the layers below it.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlendMode {
+    /// the layer replaces everything below it
+    Overwrite,
+    /// pixels are on if they are on in the layer or below it
+    Or,
+    /// pixels that are on in the layer toggle the pixels below it
+    Xor,
+    /// only pixels that are on in the layer stay visible below it
+    Mask,
+}
+
+/// Which senders a [LayerRule] applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceMatcher {
+    Address(SocketAddr),
+    Ip(IpAddr),
+    Port(u16),
+}
+
+/// Configures the layer of all senders matching `source`.
+///
+/// Parsed from `<SOURCE>=<PRIORITY>[,<BLEND>]`, where source is an IP address,
+/// an IP address with port or only a source port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LayerRule {
+    pub source: SourceMatcher,
+    pub priority: i32,
+    pub blend: Option<BlendMode>,
+}
+
+/// Combines the virtual displays of all senders into one frame.
+///
+/// Layers are stacked by ascending priority, senders with the same priority
+/// in the order they were first seen.
+/// The brightness of a tile is taken from the topmost layer that has a pixel
+/// turned on in that tile.
+#[derive(Debug)]
+pub struct Compositor<'t> {
+    senders: &'t SenderDisplays,
+    shared: &'t Simulator,
+    rules: Vec<LayerRule>,
+    default_blend: BlendMode,
+    /// whether a sender display changed since the last [Compositor::composite]
+    changed: Cell<bool>,
+}
+
+impl SourceMatcher {
+    fn matches(&self, source: SocketAddr) -> bool {
+        match self {
+            SourceMatcher::Address(addr) => *addr == source,
+            SourceMatcher::Ip(ip) => *ip == source.ip(),
+            SourceMatcher::Port(port) => *port == source.port(),
+        }
+    }
+}
+
+impl FromStr for SourceMatcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            Ok(SourceMatcher::Address(addr))
+        } else if let Ok(ip) = s.parse() {
+            Ok(SourceMatcher::Ip(ip))
+        } else if let Ok(port) = s.parse() {
+            Ok(SourceMatcher::Port(port))
+        } else {
+            Err(format!("'{s}' is not an address, IP or port"))
+        }
+    }
+}
+
+impl FromStr for LayerRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (source, layer) = s.rsplit_once('=').ok_or_else(|| {
+            format!("expected <SOURCE>=<PRIORITY>, got '{s}'")
+        })?;
+        let (priority, blend) = match layer.split_once(',') {
+            Some((priority, blend)) => {
+                (priority, Some(BlendMode::from_str(blend, true)?))
+            }
+            None => (layer, None),
+        };
+        let priority = priority
+            .parse()
+            .map_err(|e| format!("invalid priority '{priority}': {e}"))?;
+        Ok(LayerRule {
+            source: source.parse()?,
+            priority,
+            blend,
+        })
+    }
+}
+
+impl<'t> Compositor<'t> {
+    pub fn new(
+        senders: &'t SenderDisplays,
+        shared: &'t Simulator,
+        rules: Vec<LayerRule>,
+        default_blend: BlendMode,
+    ) -> Self {
+        Self {
+            senders,
+            shared,
+            rules,
+            default_blend,
+            changed: Cell::new(false),
+        }
+    }
+
+    pub fn senders(&self) -> &'t SenderDisplays {
+        self.senders
+    }
+
+    /// Has to be called after executing a command on the display of a sender.
+    pub fn sender_changed(&self) {
+        self.changed.set(true);
+    }
+
+    /// Renders all layers into the shared display, if a sender changed since the last time.
+    ///
+    /// Only needed before the shared display is shown, so commands are not composited one by one.
+    pub fn composite(&self) {
+        if !self.changed.replace(false) {
+            return;
+        }
+        let mut layers = self
+            .senders
+            .all()
+            .into_iter()
+            .map(|sender| {
+                let (priority, blend) = self.layer_of(sender.address());
+                (priority, blend, sender)
+            })
+            .collect::<Vec<_>>();
+        layers.sort_by_key(|(priority, _, _)| *priority);
+
+        let mut display = Bitmap::max_sized();
+        let mut luma = BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT);
+        luma.fill(Brightness::MAX);
+
+        for (_, blend, sender) in layers {
+            let (layer, layer_luma) = sender.simulator.snapshot().into_parts();
+            let blend_byte = match blend {
+                BlendMode::Overwrite => |_: u8, new: u8| new,
+                BlendMode::Or => |old: u8, new: u8| old | new,
+                BlendMode::Xor => |old: u8, new: u8| old ^ new,
+                BlendMode::Mask => |old: u8, new: u8| old & new,
+            };
+            for (old, new) in
+                display.data_ref_mut().iter_mut().zip(layer.data_ref())
+            {
+                *old = blend_byte(*old, *new);
+            }
+
+            for tile_y in 0..TILE_HEIGHT {
+                for tile_x in 0..TILE_WIDTH {
+                    if Self::tile_has_pixels(&layer, tile_x, tile_y) {
+                        luma.set(
+                            tile_x,
+                            tile_y,
+                            layer_luma.get(tile_x, tile_y),
+                        );
+                    }
+                }
+            }
+        }
+        self.shared.restore(
+            Snapshot::new(display, luma)
+                .expect("composited onto a display sized bitmap"),
+        );
+    }
+
+    fn layer_of(&self, source: SocketAddr) -> (i32, BlendMode) {
+        self.rules
+            .iter()
+            .find(|rule| rule.source.matches(source))
+            .map_or((0, self.default_blend), |rule| {
+                (rule.priority, rule.blend.unwrap_or(self.default_blend))
+            })
+    }
+
+    fn tile_has_pixels(bitmap: &Bitmap, tile_x: usize, tile_y: usize) -> bool {
+        // with a tile size of 8, every byte of a row is exactly one tile wide
+        let row_bytes = bitmap.width() / TILE_SIZE;
+        let data = bitmap.data_ref();
+        (tile_y * TILE_SIZE..(tile_y + 1) * TILE_SIZE)
+            .any(|y| data[y * row_bytes + tile_x] != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(text: &str) -> LayerRule {
+        text.parse().unwrap()
+    }
+
+    fn addr(text: &str) -> SocketAddr {
+        text.parse().unwrap()
+    }
+
+    /// A layer with the first byte of pixels set, and the brightness of the first tile.
+    fn show(sender: &Simulator, first_byte: u8, brightness: u8) {
+        let mut bitmap = Bitmap::max_sized();
+        bitmap.data_ref_mut()[0] = first_byte;
+        let mut luma = BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT);
+        luma.set(0, 0, Brightness::try_from(brightness).unwrap());
+        sender.restore(Snapshot::new(bitmap, luma).unwrap());
+    }
+
+    fn first_byte(simulator: &Simulator) -> u8 {
+        simulator.bitmap().data_ref()[0]
+    }
+
+    fn first_tile_brightness(simulator: &Simulator) -> u8 {
+        u8::from(simulator.brightness().get(0, 0))
+    }
+
+    #[test]
+    fn parse_layer_rules() {
+        assert_eq!(
+            rule("10.0.0.1:2342=5,xor"),
+            LayerRule {
+                source: SourceMatcher::Address(addr("10.0.0.1:2342")),
+                priority: 5,
+                blend: Some(BlendMode::Xor),
+            }
+        );
+        assert_eq!(
+            rule("::1=-3"),
+            LayerRule {
+                source: SourceMatcher::Ip("::1".parse().unwrap()),
+                priority: -3,
+                blend: None,
+            }
+        );
+        assert_eq!(
+            rule("[::1]:80=0,MASK").source,
+            SourceMatcher::Address(addr("[::1]:80"))
+        );
+        assert_eq!(
+            rule("2342=1,or"),
+            LayerRule {
+                source: SourceMatcher::Port(2342),
+                priority: 1,
+                blend: Some(BlendMode::Or),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_layer_rule_errors() {
+        for invalid in [
+            "",
+            "10.0.0.1",
+            "10.0.0.1=",
+            "10.0.0.1=high",
+            "10.0.0.1=1,",
+            "10.0.0.1=1,blend",
+            "host=1",
+            "70000=1",
+            "=1",
+        ] {
+            assert!(invalid.parse::<LayerRule>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn source_matchers() {
+        let source = addr("10.0.0.1:2342");
+        assert!(rule("10.0.0.1:2342=0").source.matches(source));
+        assert!(!rule("10.0.0.1:2343=0").source.matches(source));
+        assert!(rule("10.0.0.1=0").source.matches(source));
+        assert!(!rule("10.0.0.2=0").source.matches(source));
+        assert!(rule("2342=0").source.matches(source));
+        assert!(!rule("2343=0").source.matches(source));
+    }
+
+    #[test]
+    fn blend_modes() {
+        let shared = Simulator::default();
+        let senders = SenderDisplays::new(8, None);
+        let below = addr("10.0.0.1:1");
+        let above = addr("10.0.0.2:1");
+        // inserted top first, so only the priority decides the order
+        show(&senders.get_or_insert(above, &shared).simulator, 0b1010, 1);
+        show(&senders.get_or_insert(below, &shared).simulator, 0b1100, 1);
+
+        for (blend, expected) in [
+            ("overwrite", 0b1010),
+            ("or", 0b1110),
+            ("xor", 0b0110),
+            ("mask", 0b1000),
+        ] {
+            let rules = vec![
+                rule("10.0.0.1=0,or"),
+                rule(&format!("10.0.0.2=1,{blend}")),
+            ];
+            let compositor =
+                Compositor::new(&senders, &shared, rules, BlendMode::Or);
+            compositor.sender_changed();
+            compositor.composite();
+            assert_eq!(first_byte(&shared), expected, "{blend}");
+        }
+    }
+
+    #[test]
+    fn default_blend_applies_without_rule() {
+        let shared = Simulator::default();
+        let senders = SenderDisplays::new(8, None);
+        show(
+            &senders.get_or_insert(addr("10.0.0.1:1"), &shared).simulator,
+            0b1100,
+            1,
+        );
+        show(
+            &senders.get_or_insert(addr("10.0.0.2:1"), &shared).simulator,
+            0b1010,
+            1,
+        );
+
+        let compositor =
+            Compositor::new(&senders, &shared, Vec::new(), BlendMode::Xor);
+        compositor.sender_changed();
+        compositor.composite();
+
+        assert_eq!(first_byte(&shared), 0b0110);
+    }
+
+    #[test]
+    fn priority_orders_layers() {
+        let shared = Simulator::default();
+        let senders = SenderDisplays::new(8, None);
+        show(
+            &senders.get_or_insert(addr("10.0.0.1:1"), &shared).simulator,
+            0b0001,
+            3,
+        );
+        show(
+            &senders.get_or_insert(addr("10.0.0.2:1"), &shared).simulator,
+            0b0010,
+            5,
+        );
+        show(
+            &senders.get_or_insert(addr("10.0.0.3:1"), &shared).simulator,
+            0b0100,
+            7,
+        );
+
+        // the first seen sender is on top, the two others share a priority below it
+        let rules =
+            vec![rule("10.0.0.1=1"), rule("10.0.0.2=0"), rule("10.0.0.3=0")];
+        let compositor =
+            Compositor::new(&senders, &shared, rules, BlendMode::Overwrite);
+        compositor.sender_changed();
+        compositor.composite();
+        assert_eq!(first_byte(&shared), 0b0001);
+        assert_eq!(first_tile_brightness(&shared), 3);
+
+        // with the same priority, the sender seen later is on top
+        let rules = vec![rule("10.0.0.1=-1")];
+        let compositor =
+            Compositor::new(&senders, &shared, rules, BlendMode::Overwrite);
+        compositor.sender_changed();
+        compositor.composite();
+        assert_eq!(first_byte(&shared), 0b0100);
+        assert_eq!(first_tile_brightness(&shared), 7);
+    }
+
+    #[test]
+    fn brightness_of_topmost_layer_with_pixels() {
+        let shared = Simulator::default();
+        let senders = SenderDisplays::new(8, None);
+        show(
+            &senders.get_or_insert(addr("10.0.0.1:1"), &shared).simulator,
+            0b0001,
+            3,
+        );
+        // no pixels in the tile, so its brightness does not count
+        show(
+            &senders.get_or_insert(addr("10.0.0.2:1"), &shared).simulator,
+            0,
+            9,
+        );
+
+        let compositor =
+            Compositor::new(&senders, &shared, Vec::new(), BlendMode::Or);
+        compositor.sender_changed();
+        compositor.composite();
+
+        assert_eq!(first_tile_brightness(&shared), 3);
+        // tiles without pixels in any layer are fully bright
+        assert_eq!(shared.brightness().get(1, 0), Brightness::MAX);
+    }
+
+    #[test]
+    fn composites_only_after_change() {
+        let shared = Simulator::default();
+        let senders = SenderDisplays::new(8, None);
+        let sender = senders.get_or_insert(addr("10.0.0.1:1"), &shared);
+        let compositor =
+            Compositor::new(&senders, &shared, Vec::new(), BlendMode::Or);
+
+        show(&sender.simulator, 0b1000, 1);
+        compositor.composite();
+        assert_eq!(first_byte(&shared), 0);
+
+        compositor.sender_changed();
+        compositor.composite();
+        assert_eq!(first_byte(&shared), 0b1000);
+
+        // nothing to do until the next change
+        show(&shared, 0, 1);
+        compositor.composite();
+        assert_eq!(first_byte(&shared), 0);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::command_kind::compression_of;
+use bzip2::bufread::BzDecoder;
+use flate2::bufread::ZlibDecoder;
+use servicepoint::{
+    CompressionCode, Grid, Packet, TypedCommand, PIXEL_COUNT, PIXEL_HEIGHT,
+    PIXEL_WIDTH, TILE_HEIGHT, TILE_WIDTH,
+};
+use std::io::Read;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// Something the decoder accepts, but the documented protocol does not allow.
+#[derive(Debug, thiserror::Error)]
+pub enum Deviation {
+    #[error(
+        "the packet filled the receive buffer and may have been truncated"
+    )]
+    MaybeTruncated,
+    #[error("{count} bytes after the end of the compressed payload")]
+    TrailingBytes {
+        /// index of the first trailing byte in the packet
+        start: usize,
+        count: usize,
+    },
+    #[error("the area at {x} {y} with a size of {width}x{height} {unit} does not fit on the display")]
+    AreaOutOfBounds {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        unit: &'static str,
+    },
+    #[error("{length} pixels at offset {offset} do not fit on the display")]
+    OffsetOutOfBounds { offset: usize, length: usize },
+}
+
+/// Lists everything in a successfully decoded packet that deviates from the protocol.
+pub fn deviations(
+    packet: &[u8],
+    command: &TypedCommand,
+    may_be_truncated: bool,
+) -> Vec<Deviation> {
+    let mut deviations = Vec::new();
+    if may_be_truncated {
+        deviations.push(Deviation::MaybeTruncated);
+    }
+    if let Some(deviation) = trailing_bytes(packet) {
+        deviations.push(deviation);
+    }
+    if let Some(deviation) = out_of_bounds(command) {
+        deviations.push(deviation);
+    }
+    deviations
+}
+
+fn trailing_bytes(packet: &[u8]) -> Option<Deviation> {
+    let Packet { header, payload } = Packet::try_from(packet).ok()?;
+    let payload = payload?;
+    let length = compressed_length(compression_of(&header)?, &payload)?;
+    let count = payload.len() - length;
+    (count > 0).then(|| Deviation::TrailingBytes {
+        start: packet.len() - count,
+        count,
+    })
+}
+
+/// The length of the compressed data at the start of the payload, if it can be decompressed.
+///
+/// The decoders of the servicepoint crate stop at the end of the compressed
+/// data and ignore everything after it.
+fn compressed_length(
+    compression: CompressionCode,
+    payload: &[u8],
+) -> Option<usize> {
+    let rest = match compression {
+        CompressionCode::Uncompressed => return None,
+        CompressionCode::Zlib => {
+            let mut decoder = ZlibDecoder::new(payload);
+            decoder.read_to_end(&mut Vec::new()).ok()?;
+            decoder.into_inner()
+        }
+        CompressionCode::Bzip2 => {
+            let mut decoder = BzDecoder::new(payload);
+            decoder.read_to_end(&mut Vec::new()).ok()?;
+            decoder.into_inner()
+        }
+        CompressionCode::Zstd => {
+            // further frames are decoded as well, but skippable frames add nothing
+            let mut decoder =
+                ZstdDecoder::with_buffer(payload).ok()?.single_frame();
+            decoder.read_to_end(&mut Vec::new()).ok()?;
+            decoder.finish()
+        }
+        CompressionCode::Lzma => return lzma_length(payload),
+    };
+    Some(payload.len() - rest.len())
+}
+
+/// The decoder keeps the input after the compressed data to itself,
+/// so this finds the shortest start of the payload that still decompresses.
+fn lzma_length(payload: &[u8]) -> Option<usize> {
+    lzma::decompress(payload).ok()?;
+    let (mut too_short, mut long_enough) = (0, payload.len());
+    while long_enough - too_short > 1 {
+        let middle = too_short + (long_enough - too_short) / 2;
+        if lzma::decompress(&payload[..middle]).is_ok() {
+            long_enough = middle;
+        } else {
+            too_short = middle;
+        }
+    }
+    Some(long_enough)
+}
+
+/// Finds areas that the decoder accepts, but that would only be drawn in part.
+fn out_of_bounds(command: &TypedCommand) -> Option<Deviation> {
+    match command {
+        TypedCommand::Bitmap(command) => area_out_of_bounds(
+            (command.origin.x, command.origin.y),
+            (command.bitmap.width(), command.bitmap.height()),
+            (PIXEL_WIDTH, PIXEL_HEIGHT),
+            "pixels",
+        ),
+        TypedCommand::BitVec(command) => {
+            let (offset, length) = (command.offset, command.bitvec.len());
+            (offset + length > PIXEL_COUNT)
+                .then_some(Deviation::OffsetOutOfBounds { offset, length })
+        }
+        TypedCommand::Cp437Grid(command) => area_out_of_bounds(
+            (command.origin.x, command.origin.y),
+            (command.grid.width(), command.grid.height()),
+            (TILE_WIDTH, TILE_HEIGHT),
+            "tiles",
+        ),
+        TypedCommand::CharGrid(command) => area_out_of_bounds(
+            (command.origin.x, command.origin.y),
+            (command.grid.width(), command.grid.height()),
+            (TILE_WIDTH, TILE_HEIGHT),
+            "tiles",
+        ),
+        TypedCommand::BrightnessGrid(command) => area_out_of_bounds(
+            (command.origin.x, command.origin.y),
+            (command.grid.width(), command.grid.height()),
+            (TILE_WIDTH, TILE_HEIGHT),
+            "tiles",
+        ),
+        _ => None,
+    }
+}
+
+fn area_out_of_bounds(
+    (x, y): (usize, usize),
+    (width, height): (usize, usize),
+    (max_width, max_height): (usize, usize),
+    unit: &'static str,
+) -> Option<Deviation> {
+    (x + width > max_width || y + height > max_height).then_some(
+        Deviation::AreaOutOfBounds {
+            x,
+            y,
+            width,
+            height,
+            unit,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use servicepoint::{
+        BitVecCommand, Bitmap, BitmapCommand, ClearCommand, Cp437Grid,
+        Cp437GridCommand, DisplayBitVec, Origin, TILE_SIZE,
+    };
+
+    /// A zstd frame the decoder skips, see RFC 8878, section 3.1.2.
+    const SKIPPABLE_FRAME: [u8; 8] = [0x50, 0x2A, 0x4D, 0x18, 0, 0, 0, 0];
+
+    fn bitmap_command(compression: CompressionCode) -> TypedCommand {
+        let mut bitmap = Bitmap::new(4 * TILE_SIZE, 4).unwrap();
+        bitmap.set(3, 1, true);
+        bitmap.set(20, 2, true);
+        BitmapCommand {
+            bitmap,
+            origin: Origin::new(8, 16),
+            compression,
+        }
+        .into()
+    }
+
+    fn packet_of(command: &TypedCommand) -> Vec<u8> {
+        let packet = match command.clone() {
+            TypedCommand::Bitmap(command) => Packet::try_from(command),
+            TypedCommand::BitVec(command) => Packet::try_from(command),
+            TypedCommand::Cp437Grid(command) => Packet::try_from(command),
+            TypedCommand::Clear(command) => Ok(Packet::from(command)),
+            command => panic!("no packet for {command:?}"),
+        };
+        packet.unwrap().into()
+    }
+
+    fn compressions() -> impl Iterator<Item = CompressionCode> {
+        [
+            CompressionCode::Zlib,
+            CompressionCode::Bzip2,
+            CompressionCode::Lzma,
+            CompressionCode::Zstd,
+        ]
+        .into_iter()
+    }
+
+    #[test]
+    fn conforming_packets() {
+        let commands = compressions()
+            .chain([CompressionCode::Uncompressed])
+            .map(bitmap_command)
+            .chain([TypedCommand::Clear(ClearCommand)]);
+        for command in commands {
+            let packet = packet_of(&command);
+            let deviations = deviations(&packet, &command, false);
+            assert!(deviations.is_empty(), "{command:?}: {deviations:?}");
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_of_every_codec() {
+        for compression in compressions() {
+            let command = bitmap_command(compression);
+            let mut packet = packet_of(&command);
+            let start = packet.len();
+            let trailing: &[u8] = match compression {
+                // anything else after the frame fails to decode
+                CompressionCode::Zstd => &SKIPPABLE_FRAME,
+                _ => &[0xDE, 0xAD, 0xBE],
+            };
+            packet.extend_from_slice(trailing);
+
+            // the decoder accepts the packet
+            let packet_with_trailing = Packet::try_from(packet.as_slice());
+            let decoded =
+                TypedCommand::try_from(packet_with_trailing.unwrap()).unwrap();
+            assert_eq!(decoded, command, "{compression:?}");
+
+            let deviations = deviations(&packet, &command, false);
+            assert!(
+                matches!(
+                    deviations[..],
+                    [Deviation::TrailingBytes { start: found, count }]
+                        if found == start && count == trailing.len()
+                ),
+                "{compression:?}: {deviations:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn maybe_truncated() {
+        let command = bitmap_command(CompressionCode::Uncompressed);
+        let deviations = deviations(&packet_of(&command), &command, true);
+        assert!(matches!(deviations[..], [Deviation::MaybeTruncated]));
+    }
+
+    #[test]
+    fn area_out_of_bounds() {
+        let bitmap = BitmapCommand {
+            bitmap: Bitmap::new(2 * TILE_SIZE, 1).unwrap(),
+            origin: Origin::new(PIXEL_WIDTH - TILE_SIZE, 0),
+            compression: CompressionCode::Uncompressed,
+        }
+        .into();
+        let grid = Cp437GridCommand {
+            grid: Cp437Grid::new(2, 3),
+            origin: Origin::new(1, TILE_HEIGHT - 2),
+        }
+        .into();
+
+        for (command, expected) in [
+            (
+                bitmap,
+                (PIXEL_WIDTH - TILE_SIZE, 0, 2 * TILE_SIZE, 1, "pixels"),
+            ),
+            (grid, (1, TILE_HEIGHT - 2, 2, 3, "tiles")),
+        ] {
+            let deviations = deviations(&packet_of(&command), &command, false);
+            let [Deviation::AreaOutOfBounds {
+                x,
+                y,
+                width,
+                height,
+                unit,
+            }] = deviations[..]
+            else {
+                panic!("{command:?}: {deviations:?}");
+            };
+            assert_eq!((x, y, width, height, unit), expected);
+        }
+    }
+
+    #[test]
+    fn offset_out_of_bounds() {
+        // the offset has to fit into the header
+        let offset = 60000;
+        let command = BitVecCommand {
+            offset,
+            bitvec: DisplayBitVec::repeat(true, PIXEL_COUNT - offset + 8),
+            operation: servicepoint::BinaryOperation::Or,
+            compression: CompressionCode::Uncompressed,
+        }
+        .into();
+
+        let deviations = deviations(&packet_of(&command), &command, false);
+
+        assert!(
+            matches!(
+                deviations[..],
+                [Deviation::OffsetOutOfBounds { offset: found, length }]
+                    if found == offset && length == PIXEL_COUNT - offset + 8
+            ),
+            "{deviations:?}"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use servicepoint::{
+    Bitmap, Brightness, Grid, ValueGrid, PIXEL_HEIGHT, PIXEL_WIDTH,
+    TILE_HEIGHT, TILE_SIZE, TILE_WIDTH,
+};
+use servicepoint_simulator::snapshot::Snapshot;
+use std::{fs, path::Path};
+
+/// Simulated hardware defects of the display, applied to every presented state,
+/// so the window, the web viewer and `check` all see the damaged display.
+///
+/// Loaded from a text file with one defect per line:
+///
+/// ```text
+/// # pixels that are always off
+/// dead <x> <y>
+/// # pixels that are always on
+/// stuck <x> <y>
+/// # tiles that ignore all changes and keep showing the first presented state
+/// frozen-tile <tile_x> <tile_y>
+/// # tiles that are brighter or darker than configured, e.g. 0.5 for half the brightness
+/// dim-tile <tile_x> <tile_y> <multiplier>
+/// ```
+///
+/// The first presented state of a display is the one it starts with,
+/// e.g. the `--initial-state` or the empty virtual display of a new sender.
+#[derive(Debug, Clone)]
+pub struct Defects {
+    dead: Bitmap,
+    stuck: Bitmap,
+    frozen: ValueGrid<bool>,
+    brightness: ValueGrid<f32>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DefectsError {
+    #[error("could not read defects file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("line {line}: {message}")]
+    InvalidLine { line: usize, message: String },
+}
+
+impl Defects {
+    pub fn load(path: &Path) -> Result<Self, DefectsError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, DefectsError> {
+        let mut defects = Self::default();
+        for (index, line) in text.lines().enumerate() {
+            defects.parse_line(line).map_err(|message| {
+                DefectsError::InvalidLine {
+                    line: index + 1,
+                    message,
+                }
+            })?;
+        }
+        Ok(defects)
+    }
+
+    /// Returns what a display with these defects shows for `state`.
+    ///
+    /// Frozen tiles keep the pixels and brightness of `shown`, the state the display showed before.
+    pub fn damage(&self, state: Snapshot, shown: &Snapshot) -> Snapshot {
+        let (mut bitmap, mut brightness) = state.into_parts();
+        for tile_y in 0..TILE_HEIGHT {
+            for tile_x in 0..TILE_WIDTH {
+                if !self.frozen.get(tile_x, tile_y) {
+                    let multiplier = self.brightness.get(tile_x, tile_y);
+                    let value = u8::from(brightness.get(tile_x, tile_y));
+                    let value = (value as f32 * multiplier).round() as u8;
+                    brightness.set(
+                        tile_x,
+                        tile_y,
+                        Brightness::saturating_from(value),
+                    );
+                    continue;
+                }
+
+                brightness.set(
+                    tile_x,
+                    tile_y,
+                    shown.brightness().get(tile_x, tile_y),
+                );
+                for y in tile_y * TILE_SIZE..(tile_y + 1) * TILE_SIZE {
+                    for x in tile_x * TILE_SIZE..(tile_x + 1) * TILE_SIZE {
+                        bitmap.set(x, y, shown.bitmap().get(x, y));
+                    }
+                }
+            }
+        }
+
+        for y in 0..PIXEL_HEIGHT {
+            for x in 0..PIXEL_WIDTH {
+                let is_on = (bitmap.get(x, y) || self.stuck.get(x, y))
+                    && !self.dead.get(x, y);
+                bitmap.set(x, y, is_on);
+            }
+        }
+        Snapshot::new(bitmap, brightness)
+            .expect("the state has the size of the display")
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let mut parts = line.split_whitespace();
+        let Some(kind) = parts.next() else {
+            return Ok(()); // empty line or comment
+        };
+        let args = parts.collect::<Vec<_>>();
+
+        let (expected_args, width, height) = match kind {
+            "dead" | "stuck" => (2, PIXEL_WIDTH, PIXEL_HEIGHT),
+            "frozen-tile" => (2, TILE_WIDTH, TILE_HEIGHT),
+            "dim-tile" => (3, TILE_WIDTH, TILE_HEIGHT),
+            _ => return Err(format!("unknown defect '{kind}'")),
+        };
+        if args.len() != expected_args {
+            return Err(format!(
+                "'{kind}' expects {expected_args} arguments, got {}",
+                args.len()
+            ));
+        }
+        let x = parse_coordinate(args[0])?;
+        let y = parse_coordinate(args[1])?;
+        if x >= width || y >= height {
+            return Err(format!(
+                "{x} {y} is outside of the {width}x{height} grid"
+            ));
+        }
+
+        match kind {
+            "dead" => self.dead.set(x, y, true),
+            "stuck" => self.stuck.set(x, y, true),
+            "frozen-tile" => self.frozen.set(x, y, true),
+            "dim-tile" => {
+                let multiplier = args[2]
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|m| m.is_finite() && *m >= 0.0)
+                    .ok_or_else(|| {
+                        format!("invalid multiplier '{}'", args[2])
+                    })?;
+                self.brightness.set(x, y, multiplier);
+            }
+            _ => unreachable!("kind was checked above"),
+        }
+        Ok(())
+    }
+}
+
+fn parse_coordinate(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid coordinate '{value}'"))
+}
+
+impl Default for Defects {
+    fn default() -> Self {
+        let mut brightness = ValueGrid::new(TILE_WIDTH, TILE_HEIGHT);
+        brightness.fill(1.0);
+        Self {
+            dead: Bitmap::max_sized(),
+            stuck: Bitmap::max_sized(),
+            frozen: ValueGrid::new(TILE_WIDTH, TILE_HEIGHT),
+            brightness,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use servicepoint::BrightnessGrid;
+
+    fn state(is_on: bool, brightness: u8) -> Snapshot {
+        let mut bitmap = Bitmap::max_sized();
+        bitmap.fill(is_on);
+        let mut luma = BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT);
+        luma.fill(Brightness::saturating_from(brightness));
+        Snapshot::new(bitmap, luma).unwrap()
+    }
+
+    fn parse_error(text: &str) -> (usize, String) {
+        match Defects::parse(text) {
+            Err(DefectsError::InvalidLine { line, message }) => (line, message),
+            other => panic!("expected an invalid line, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_all_kinds_with_comments_and_empty_lines() {
+        let defects = Defects::parse(
+            "# broken pixels\n\ndead 1 2\nstuck 447 159 # corner\n  frozen-tile 3 4\ndim-tile 55 19 0.5\n",
+        )
+        .unwrap();
+        assert!(defects.dead.get(1, 2));
+        assert!(defects.stuck.get(447, 159));
+        assert!(defects.frozen.get(3, 4));
+        assert_eq!(defects.brightness.get(55, 19), 0.5);
+        assert_eq!(defects.brightness.get(0, 0), 1.0);
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        assert_eq!(
+            parse_error("dead 1 2\nbroken 1 2"),
+            (2, String::from("unknown defect 'broken'"))
+        );
+        assert_eq!(
+            parse_error("stuck 1"),
+            (1, String::from("'stuck' expects 2 arguments, got 1"))
+        );
+        assert_eq!(
+            parse_error("dim-tile 1 2"),
+            (1, String::from("'dim-tile' expects 3 arguments, got 2"))
+        );
+        assert_eq!(
+            parse_error("dead 448 0"),
+            (1, String::from("448 0 is outside of the 448x160 grid"))
+        );
+        assert_eq!(
+            parse_error("frozen-tile 0 20"),
+            (1, String::from("0 20 is outside of the 56x20 grid"))
+        );
+        assert_eq!(
+            parse_error("dead -1 0"),
+            (1, String::from("invalid coordinate '-1'"))
+        );
+        for multiplier in ["-1", "NaN", "inf", "half"] {
+            assert_eq!(
+                parse_error(&format!("dim-tile 0 0 {multiplier}")),
+                (1, format!("invalid multiplier '{multiplier}'"))
+            );
+        }
+    }
+
+    #[test]
+    fn damages_pixels_and_brightness() {
+        let defects = Defects::parse(
+            "dead 0 0\nstuck 1 0\ndim-tile 1 0 0.5\ndim-tile 2 0 4",
+        )
+        .unwrap();
+        let off = defects.damage(state(false, 10), &state(false, 10));
+        assert!(!off.bitmap().get(0, 0));
+        assert!(off.bitmap().get(1, 0));
+        assert!(!off.bitmap().get(2, 0));
+
+        let on = defects.damage(state(true, 10), &state(false, 10));
+        assert!(!on.bitmap().get(0, 0));
+        assert!(on.bitmap().get(2, 0));
+        assert_eq!(u8::from(on.brightness().get(0, 0)), 10);
+        assert_eq!(u8::from(on.brightness().get(1, 0)), 5);
+        assert_eq!(on.brightness().get(2, 0), Brightness::MAX);
+    }
+
+    #[test]
+    fn frozen_tile_keeps_shown_state() {
+        let defects =
+            Defects::parse("frozen-tile 1 0\ndim-tile 1 0 0.5").unwrap();
+        let shown = state(false, 3);
+        let damaged = defects.damage(state(true, 10), &shown);
+        assert!(damaged.bitmap().get(0, 0));
+        assert!(!damaged.bitmap().get(TILE_SIZE, 0));
+        assert!(!damaged.bitmap().get(2 * TILE_SIZE - 1, TILE_SIZE - 1));
+        assert!(damaged.bitmap().get(2 * TILE_SIZE, 0));
+        // the shown state already is dimmed
+        assert_eq!(u8::from(damaged.brightness().get(1, 0)), 3);
+        assert_eq!(u8::from(damaged.brightness().get(0, 0)), 10);
+    }
+}
@@ -0,0 +1,783 @@
+use crate::conformance::Deviation;
+use log::{log_enabled, warn, Level};
+use servicepoint::{
+    CommandCode, CompressionCode, Header, Packet, TryFromPacketError,
+    PIXEL_COUNT, PIXEL_HEIGHT, TILE_HEIGHT, TILE_SIZE, TILE_WIDTH,
+};
+use std::{fmt::Write as _, net::SocketAddr, ops::Range};
+
+/// Log target of the explanations, only enabled with `--diagnostics`.
+pub const DIAGNOSTICS_LOG_TARGET: &str = "diagnostics";
+
+const HEADER_SIZE: usize = size_of::<Header>();
+const DUMP_WIDTH: usize = 16;
+/// Longer packets are cut off in the hex dump, marked bytes are always shown.
+const DUMP_CONTEXT_ROWS: usize = 4;
+
+/// Why a packet could not be used.
+#[derive(Debug)]
+pub enum Problem<'a> {
+    TooShort,
+    Decode(&'a TryFromPacketError),
+    Nonconforming(&'a [Deviation]),
+    Execution,
+}
+
+/// What a header field means for a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Unused,
+    TileX,
+    TileY,
+    PixelY,
+    WidthInTiles,
+    HeightInTiles,
+    HeightInPixels,
+    OffsetInPixels,
+    LengthInBytes,
+    Compression,
+}
+
+/// The documented layout of the packets of a command.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    fields: [Field; 4],
+    payload: PayloadSize,
+    compression: Option<CompressionCode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PayloadSize {
+    Empty,
+    Exactly(usize),
+    /// field c times field d, after decompression
+    Area,
+    /// the value of field b, after decompression
+    Length,
+}
+
+/// Logs a detailed explanation of why a packet could not be used, if diagnostics are enabled.
+pub fn explain(source: SocketAddr, packet: &[u8], problem: Problem) {
+    if !log_enabled!(target: DIAGNOSTICS_LOG_TARGET, Level::Warn) {
+        return;
+    }
+    warn!(target: DIAGNOSTICS_LOG_TARGET, "{}", diagnose(source, packet, &problem));
+}
+
+fn diagnose(source: SocketAddr, packet: &[u8], problem: &Problem) -> String {
+    let mut out = format!("packet from {source} with {} bytes ", packet.len());
+    let _ = match problem {
+        Problem::TooShort => writeln!(out, "is shorter than a header"),
+        Problem::Decode(err) => writeln!(out, "could not be decoded: {err}"),
+        Problem::Nonconforming(_) => {
+            writeln!(out, "deviates from the protocol")
+        }
+        Problem::Execution => writeln!(out, "could not be executed"),
+    };
+
+    let mut marked = Vec::new();
+    let mut hints = Vec::new();
+    let header = packet
+        .get(..HEADER_SIZE)
+        .and_then(|header| Packet::try_from(header).ok())
+        .map(|packet| packet.header);
+    let Some(header) = header else {
+        marked.push(0..packet.len());
+        hints.push(format!(
+            "every packet starts with a {HEADER_SIZE} byte header: the command code and the fields a, b, c and d, each a 16 bit big-endian number"
+        ));
+        write_dump(&mut out, packet, &marked);
+        write_hints(&mut out, &hints);
+        return out;
+    };
+
+    let command = CommandCode::try_from(header.command_code).ok();
+    let layout = command.map(layout);
+    write_header(&mut out, &header, command, layout);
+    let payload = &packet[HEADER_SIZE..];
+    let _ = writeln!(out, "payload: {} bytes", payload.len());
+
+    match (problem, layout) {
+        (Problem::Decode(TryFromPacketError::InvalidCommand(_)), _)
+        | (_, None) => {
+            marked.push(0..2);
+            hints.push(unknown_command_hint(header.command_code));
+        }
+        (Problem::Decode(err), Some(layout)) => {
+            explain_decode_error(
+                err,
+                &header,
+                layout,
+                payload,
+                &mut marked,
+                &mut hints,
+            );
+        }
+        (Problem::Nonconforming(deviations), Some(_)) => {
+            explain_deviations(deviations, &mut marked, &mut hints);
+        }
+        (Problem::TooShort | Problem::Execution, Some(_)) => {}
+    }
+    if let Some(layout) = layout {
+        check_bounds(&header, layout, &mut marked, &mut hints);
+    }
+    if hints.is_empty() && matches!(problem, Problem::Execution) {
+        hints.push(String::from(
+            "the packet is valid, but executing the command failed, see the log lines before this one",
+        ));
+    }
+
+    write_dump(&mut out, packet, &marked);
+    write_hints(&mut out, &hints);
+    out
+}
+
+fn explain_decode_error(
+    err: &TryFromPacketError,
+    header: &Header,
+    layout: Layout,
+    payload: &[u8],
+    marked: &mut Vec<Range<usize>>,
+    hints: &mut Vec<String>,
+) {
+    match err {
+        TryFromPacketError::UnexpectedPayloadSize { expected, actual } => {
+            explain_payload_size(
+                header, layout, *expected, *actual, marked, hints,
+            );
+        }
+        TryFromPacketError::LoadBitmapFailed(_) => {
+            let expected = header.c as usize * header.d as usize;
+            let actual = match layout.compression {
+                Some(CompressionCode::Uncompressed) => payload.len(),
+                _ => usize::MAX,
+            };
+            explain_payload_size(
+                header, layout, expected, actual, marked, hints,
+            );
+        }
+        TryFromPacketError::ExtraneousHeaderValues => {
+            for (index, (field, value)) in
+                layout.fields.iter().zip(field_values(header)).enumerate()
+            {
+                if *field == Field::Unused && value != 0 {
+                    marked.push(field_range(index));
+                }
+            }
+            hints.push(String::from(
+                "header fields that are not used by this command have to be 0",
+            ));
+        }
+        TryFromPacketError::InvalidCompression(_) => {
+            marked.push(field_range(2));
+            hints.push(format!(
+                "{:#06x} is not a known compression code, use one of {}",
+                header.c,
+                compression_codes()
+            ));
+        }
+        TryFromPacketError::DecompressionFailed => {
+            marked.push(HEADER_SIZE..HEADER_SIZE + payload.len());
+            let codec = layout
+                .compression
+                .or_else(|| CompressionCode::try_from(header.c).ok())
+                .map_or(String::from("the declared codec"), |codec| {
+                    format!("{codec:?}")
+                });
+            hints.push(format!(
+                "the payload is not valid {codec} data, check that the compression in the command matches how the payload was compressed"
+            ));
+        }
+        TryFromPacketError::InvalidBrightness(value) => {
+            for (index, byte) in payload.iter().enumerate() {
+                if byte == value {
+                    marked.push(HEADER_SIZE + index..HEADER_SIZE + index + 1);
+                }
+            }
+            hints.push(format!(
+                "brightness values go from 0 to 11, {value} is too bright"
+            ));
+        }
+        TryFromPacketError::InvalidUtf8(err) => {
+            let start = HEADER_SIZE + err.utf8_error().valid_up_to();
+            marked.push(start..start + 1);
+            hints.push(String::from(
+                "the text is not valid UTF-8, use the CP437 command to send single byte characters",
+            ));
+        }
+        TryFromPacketError::InvalidCommand(_) => {}
+    }
+}
+
+fn explain_deviations(
+    deviations: &[Deviation],
+    marked: &mut Vec<Range<usize>>,
+    hints: &mut Vec<String>,
+) {
+    for deviation in deviations {
+        match deviation {
+            Deviation::MaybeTruncated => hints.push(format!(
+                "{deviation}, send smaller packets or use compression"
+            )),
+            Deviation::TrailingBytes { start, count } => {
+                marked.push(*start..start + count);
+                hints.push(format!(
+                    "{deviation}, the payload has to end with the compressed data"
+                ));
+            }
+            // explained together with the header fields
+            Deviation::AreaOutOfBounds { .. }
+            | Deviation::OffsetOutOfBounds { .. } => {}
+        }
+    }
+}
+
+fn explain_payload_size(
+    header: &Header,
+    layout: Layout,
+    expected: usize,
+    actual: usize,
+    marked: &mut Vec<Range<usize>>,
+    hints: &mut Vec<String>,
+) {
+    let compressed = !matches!(
+        layout.compression,
+        None | Some(CompressionCode::Uncompressed)
+    );
+    let declared_by = match layout.payload {
+        PayloadSize::Empty => String::from("this command has no payload"),
+        PayloadSize::Exactly(size) => {
+            format!("this command has a payload of exactly {size} bytes")
+        }
+        PayloadSize::Area => {
+            marked.push(field_range(2));
+            marked.push(field_range(3));
+            format!(
+                "the header declares {} x {} = {expected} bytes of payload",
+                header.c, header.d
+            )
+        }
+        PayloadSize::Length => {
+            marked.push(field_range(1));
+            format!("the header declares {expected} bytes of payload")
+        }
+    };
+    let after = if compressed {
+        " after decompression"
+    } else {
+        ""
+    };
+    if actual == usize::MAX {
+        hints.push(format!(
+            "{declared_by}, but the payload has a different size{after}"
+        ));
+    } else {
+        hints.push(format!("{declared_by}, but it has {actual} bytes{after}"));
+    }
+    if !compressed && actual > expected && actual != usize::MAX {
+        marked.push(HEADER_SIZE + expected..HEADER_SIZE + actual);
+    }
+    let width_is_tiles = layout.fields[2] == Field::WidthInTiles
+        && layout.fields[3] == Field::HeightInPixels;
+    if width_is_tiles
+        && actual != 0
+        && actual != usize::MAX
+        && actual * TILE_SIZE == expected
+    {
+        hints.push(String::from(
+            "c looks like a width in pixels, but it is counted in tiles of 8 pixels (one byte per row of a tile)",
+        ));
+    }
+}
+
+/// Explains if the area drawn by the command does not fit on the display.
+fn check_bounds(
+    header: &Header,
+    layout: Layout,
+    marked: &mut Vec<Range<usize>>,
+    hints: &mut Vec<String>,
+) {
+    let [a, b, c, d] = field_values(header).map(usize::from);
+    match layout.fields {
+        [Field::TileX, y_field, Field::WidthInTiles, _] => {
+            let (height, unit) = if y_field == Field::PixelY {
+                (PIXEL_HEIGHT, "pixels")
+            } else {
+                (TILE_HEIGHT, "tiles")
+            };
+            if a + c > TILE_WIDTH {
+                marked.push(field_range(0));
+                marked.push(field_range(2));
+                hints.push(format!(
+                    "the origin is out of range: x {a} plus width {c} is more than the {TILE_WIDTH} tiles of the display"
+                ));
+            }
+            if b + d > height {
+                marked.push(field_range(1));
+                marked.push(field_range(3));
+                hints.push(format!(
+                    "the origin is out of range: y {b} plus height {d} is more than the {height} {unit} of the display"
+                ));
+            }
+        }
+        [Field::OffsetInPixels, Field::LengthInBytes, ..]
+            if a + b * 8 > PIXEL_COUNT =>
+        {
+            marked.push(field_range(0));
+            marked.push(field_range(1));
+            hints.push(format!(
+                "the offset is out of range: offset {a} plus {b} bytes of pixels is more than the {PIXEL_COUNT} pixels of the display"
+            ));
+        }
+        _ => {}
+    }
+}
+
+fn layout(command: CommandCode) -> Layout {
+    use Field::*;
+    let (fields, payload) = match command {
+        CommandCode::Clear | CommandCode::HardReset | CommandCode::FadeOut => {
+            ([Unused; 4], PayloadSize::Empty)
+        }
+        #[allow(deprecated)]
+        CommandCode::BitmapLegacy => ([Unused; 4], PayloadSize::Empty),
+        CommandCode::Brightness => ([Unused; 4], PayloadSize::Exactly(1)),
+        CommandCode::Cp437Data
+        | CommandCode::Utf8Data
+        | CommandCode::CharBrightness => (
+            [TileX, TileY, WidthInTiles, HeightInTiles],
+            PayloadSize::Area,
+        ),
+        CommandCode::BitmapLinear
+        | CommandCode::BitmapLinearAnd
+        | CommandCode::BitmapLinearOr
+        | CommandCode::BitmapLinearXor => (
+            [OffsetInPixels, LengthInBytes, Compression, Unused],
+            PayloadSize::Length,
+        ),
+        _ => (
+            [TileX, PixelY, WidthInTiles, HeightInPixels],
+            PayloadSize::Area,
+        ),
+    };
+    Layout {
+        fields,
+        payload,
+        compression: window_compression(command),
+    }
+}
+
+/// The compression of the bitmap window commands is part of the command code.
+fn window_compression(command: CommandCode) -> Option<CompressionCode> {
+    match command {
+        CommandCode::BitmapLinearWinUncompressed => {
+            Some(CompressionCode::Uncompressed)
+        }
+        CommandCode::BitmapLinearWinZlib => Some(CompressionCode::Zlib),
+        CommandCode::BitmapLinearWinBzip2 => Some(CompressionCode::Bzip2),
+        CommandCode::BitmapLinearWinLzma => Some(CompressionCode::Lzma),
+        CommandCode::BitmapLinearWinZstd => Some(CompressionCode::Zstd),
+        _ => None,
+    }
+}
+
+fn unknown_command_hint(code: u16) -> String {
+    let mut hint = format!("{code:#06x} is not a known command code");
+    if let Ok(swapped) = CommandCode::try_from(code.swap_bytes()) {
+        let _ = write!(
+            hint,
+            ", but {:#06x} ({swapped:?}) is: the header has to be big-endian",
+            code.swap_bytes()
+        );
+    }
+    hint
+}
+
+fn compression_codes() -> String {
+    [
+        CompressionCode::Uncompressed,
+        CompressionCode::Zlib,
+        CompressionCode::Bzip2,
+        CompressionCode::Lzma,
+        CompressionCode::Zstd,
+    ]
+    .map(|code| format!("{:#06x} ({code:?})", u16::from(code)))
+    .join(", ")
+}
+
+fn field_values(header: &Header) -> [u16; 4] {
+    [header.a, header.b, header.c, header.d]
+}
+
+/// The bytes of header field a, b, c or d.
+fn field_range(index: usize) -> Range<usize> {
+    let start = 2 + index * 2;
+    start..start + 2
+}
+
+fn write_header(
+    out: &mut String,
+    header: &Header,
+    command: Option<CommandCode>,
+    layout: Option<Layout>,
+) {
+    let name = command
+        .map_or(String::from("unknown"), |command| format!("{command:?}"));
+    let _ = writeln!(out, "header:");
+    let _ =
+        writeln!(out, "  command code {:#06x}  {name}", header.command_code);
+    for (index, (name, value)) in ["a", "b", "c", "d"]
+        .iter()
+        .zip(field_values(header))
+        .enumerate()
+    {
+        let meaning = layout.map_or("", |layout| match layout.fields[index] {
+            Field::Unused => "unused, has to be 0",
+            Field::TileX => "x in tiles",
+            Field::TileY => "y in tiles",
+            Field::PixelY => "y in pixels",
+            Field::WidthInTiles => "width in tiles",
+            Field::HeightInTiles => "height in tiles",
+            Field::HeightInPixels => "height in pixels",
+            Field::OffsetInPixels => "offset in pixels",
+            Field::LengthInBytes => "payload length in bytes",
+            Field::Compression => "compression code",
+        });
+        let line = format!("  {name}            {value:<6}  {meaning}");
+        let _ = writeln!(out, "{}", line.trim_end());
+    }
+}
+
+/// Writes the bytes as hex with `^^` below the marked ones.
+fn write_dump(out: &mut String, packet: &[u8], marked: &[Range<usize>]) {
+    let is_marked =
+        |index: usize| marked.iter().any(|range| range.contains(&index));
+    let _ = writeln!(out, "hex dump:");
+    let mut skipped = false;
+    for (row, bytes) in packet.chunks(DUMP_WIDTH).enumerate() {
+        let start = row * DUMP_WIDTH;
+        let row_marked = (start..start + bytes.len()).any(is_marked);
+        if row >= DUMP_CONTEXT_ROWS && !row_marked {
+            if !skipped {
+                let _ = writeln!(out, "  ...");
+                skipped = true;
+            }
+            continue;
+        }
+        skipped = false;
+
+        let hex = bytes
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let _ = writeln!(out, "  {start:04x}  {hex}");
+        if row_marked {
+            let marks = (start..start + bytes.len())
+                .map(|index| if is_marked(index) { "^^" } else { "  " })
+                .collect::<Vec<_>>()
+                .join(" ");
+            let _ = writeln!(out, "        {}", marks.trim_end());
+        }
+    }
+}
+
+fn write_hints(out: &mut String, hints: &[String]) {
+    for hint in hints {
+        let _ = writeln!(out, "hint: {hint}");
+    }
+    // the logger adds the last line break
+    out.pop();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use servicepoint::TypedCommand;
+
+    const SOURCE: SocketAddr = SocketAddr::new(
+        std::net::IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 0, 2)),
+        4242,
+    );
+
+    fn packet(
+        command: CommandCode,
+        fields: [u16; 4],
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut packet = (command as u16).to_be_bytes().to_vec();
+        for field in fields {
+            packet.extend_from_slice(&field.to_be_bytes());
+        }
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn decode_error(packet: &[u8]) -> TryFromPacketError {
+        TypedCommand::try_from(Packet::try_from(packet).unwrap()).unwrap_err()
+    }
+
+    fn hints(diagnosis: &str) -> Vec<&str> {
+        diagnosis
+            .lines()
+            .filter_map(|line| line.strip_prefix("hint: "))
+            .collect()
+    }
+
+    /// The line of `^^` below the first row of the hex dump.
+    fn first_marks(diagnosis: &str) -> &str {
+        let mut lines =
+            diagnosis.lines().skip_while(|line| *line != "hex dump:");
+        lines
+            .nth(2)
+            .filter(|line| line.contains("^^"))
+            .unwrap_or("")
+    }
+
+    fn diagnose_decode(packet: &[u8]) -> String {
+        diagnose(SOURCE, packet, &Problem::Decode(&decode_error(packet)))
+    }
+
+    #[test]
+    fn too_short() {
+        let diagnosis = diagnose(SOURCE, &[0, 2, 0], &Problem::TooShort);
+        assert!(diagnosis.starts_with(
+            "packet from 192.168.0.2:4242 with 3 bytes is shorter than a header\n"
+        ));
+        assert!(!diagnosis.contains("\nheader:\n"));
+        assert_eq!(first_marks(&diagnosis), "        ^^ ^^ ^^");
+        assert_eq!(hints(&diagnosis).len(), 1);
+        assert!(hints(&diagnosis)[0].contains("10 byte header"));
+    }
+
+    #[test]
+    fn too_short_with_header() {
+        // a full header has nothing to explain, but is still shown
+        let packet = packet(CommandCode::Clear, [0; 4], &[]);
+        let diagnosis = diagnose(SOURCE, &packet, &Problem::TooShort);
+        assert!(diagnosis.contains("  command code 0x0002  Clear\n"));
+        assert_eq!(first_marks(&diagnosis), "");
+        assert!(hints(&diagnosis).is_empty());
+        assert!(!diagnosis.ends_with('\n'));
+    }
+
+    #[test]
+    fn decode_invalid_command() {
+        let mut packet = packet(CommandCode::Clear, [0; 4], &[]);
+        packet.swap(0, 1);
+        let diagnosis = diagnose_decode(&packet);
+        assert!(diagnosis.contains("  command code 0x0200  unknown\n"));
+        assert_eq!(first_marks(&diagnosis), "        ^^ ^^");
+        assert_eq!(
+            hints(&diagnosis),
+            ["0x0200 is not a known command code, but 0x0002 (Clear) is: the header has to be big-endian"]
+        );
+
+        let packet = [0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            hints(&diagnose_decode(&packet)),
+            ["0xffff is not a known command code"]
+        );
+    }
+
+    #[test]
+    fn decode_unexpected_payload_size() {
+        let packet = packet(CommandCode::Cp437Data, [0, 0, 2, 2], &[1, 2, 3]);
+        let diagnosis = diagnose_decode(&packet);
+        assert!(diagnosis.contains("  c            2       width in tiles\n"));
+        assert_eq!(
+            first_marks(&diagnosis),
+            "                          ^^ ^^ ^^ ^^"
+        );
+        assert_eq!(
+            hints(&diagnosis),
+            ["the header declares 2 x 2 = 4 bytes of payload, but it has 3 bytes"]
+        );
+    }
+
+    #[test]
+    fn decode_extraneous_header_values() {
+        let packet = packet(CommandCode::Clear, [0, 0, 0, 7], &[]);
+        let diagnosis = diagnose_decode(&packet);
+        assert_eq!(
+            first_marks(&diagnosis),
+            "                                ^^ ^^"
+        );
+        assert_eq!(
+            hints(&diagnosis),
+            ["header fields that are not used by this command have to be 0"]
+        );
+    }
+
+    #[test]
+    fn decode_invalid_compression() {
+        let packet = packet(CommandCode::BitmapLinear, [0, 1, 0x1234, 0], &[0]);
+        let diagnosis = diagnose_decode(&packet);
+        assert_eq!(first_marks(&diagnosis), "                          ^^ ^^");
+        assert!(hints(&diagnosis)[0]
+            .starts_with("0x1234 is not a known compression code, use one of 0x0000 (Uncompressed), 0x677a (Zlib)"));
+    }
+
+    #[test]
+    fn decode_decompression_failed() {
+        let packet =
+            packet(CommandCode::BitmapLinearWinZlib, [0, 0, 1, 1], &[1, 2, 3]);
+        let diagnosis = diagnose_decode(&packet);
+        assert_eq!(
+            first_marks(&diagnosis),
+            "                                      ^^ ^^ ^^"
+        );
+        assert!(hints(&diagnosis)[0]
+            .starts_with("the payload is not valid Zlib data"));
+    }
+
+    #[test]
+    fn decode_invalid_brightness() {
+        let packet =
+            packet(CommandCode::CharBrightness, [0, 0, 3, 1], &[11, 12, 12]);
+        let diagnosis = diagnose_decode(&packet);
+        assert_eq!(
+            first_marks(&diagnosis),
+            "                                         ^^ ^^"
+        );
+        assert_eq!(
+            hints(&diagnosis),
+            ["brightness values go from 0 to 11, 12 is too bright"]
+        );
+    }
+
+    #[test]
+    fn decode_invalid_utf8() {
+        let packet = packet(CommandCode::Utf8Data, [0, 0, 2, 1], &[b'a', 0xff]);
+        let diagnosis = diagnose_decode(&packet);
+        assert_eq!(
+            first_marks(&diagnosis),
+            "                                         ^^"
+        );
+        assert!(hints(&diagnosis)[0].starts_with("the text is not valid UTF-8"));
+    }
+
+    #[test]
+    fn decode_load_bitmap_failed() {
+        let packet = packet(
+            CommandCode::BitmapLinearWinUncompressed,
+            [0, 0, 8, 1],
+            &[0xff],
+        );
+        assert!(matches!(
+            decode_error(&packet),
+            TryFromPacketError::LoadBitmapFailed(_)
+        ));
+        let diagnosis = diagnose_decode(&packet);
+        assert_eq!(
+            hints(&diagnosis),
+            [
+                "the header declares 8 x 1 = 8 bytes of payload, but it has 1 bytes",
+                "c looks like a width in pixels, but it is counted in tiles of 8 pixels (one byte per row of a tile)",
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_empty_window() {
+        let packet =
+            packet(CommandCode::BitmapLinearWinUncompressed, [0, 0, 0, 1], &[]);
+        let diagnosis = diagnose_decode(&packet);
+        assert_eq!(
+            hints(&diagnosis),
+            ["the header declares 0 x 1 = 0 bytes of payload, but it has 0 bytes"]
+        );
+    }
+
+    #[test]
+    fn nonconforming() {
+        let packet = packet(CommandCode::Cp437Data, [0, 0, 1, 1], b"ab");
+        let deviations = [
+            Deviation::MaybeTruncated,
+            Deviation::TrailingBytes {
+                start: 11,
+                count: 1,
+            },
+        ];
+        let diagnosis =
+            diagnose(SOURCE, &packet, &Problem::Nonconforming(&deviations));
+        assert!(diagnosis.contains("bytes deviates from the protocol\n"));
+        assert_eq!(
+            first_marks(&diagnosis),
+            "                                         ^^"
+        );
+        assert_eq!(
+            hints(&diagnosis),
+            [
+                "the packet filled the receive buffer and may have been truncated, send smaller packets or use compression",
+                "1 bytes after the end of the compressed payload, the payload has to end with the compressed data",
+            ]
+        );
+    }
+
+    #[test]
+    fn nonconforming_out_of_bounds() {
+        // the deviation is explained by the bounds check of the header
+        let packet = packet(CommandCode::BitmapLinear, [0, 9000, 0, 0], &[]);
+        let deviations = [Deviation::OffsetOutOfBounds {
+            offset: 0,
+            length: 72000,
+        }];
+        let diagnosis =
+            diagnose(SOURCE, &packet, &Problem::Nonconforming(&deviations));
+        assert_eq!(first_marks(&diagnosis), "              ^^ ^^ ^^ ^^");
+        assert_eq!(
+            hints(&diagnosis),
+            ["the offset is out of range: offset 0 plus 9000 bytes of pixels is more than the 71680 pixels of the display"]
+        );
+    }
+
+    #[test]
+    fn execution() {
+        let packet = packet(CommandCode::Cp437Data, [0, 0, 2, 1], b"ab");
+        let diagnosis = diagnose(SOURCE, &packet, &Problem::Execution);
+        assert!(diagnosis.contains("bytes could not be executed\n"));
+        assert_eq!(first_marks(&diagnosis), "");
+        assert_eq!(
+            hints(&diagnosis),
+            ["the packet is valid, but executing the command failed, see the log lines before this one"]
+        );
+    }
+
+    #[test]
+    fn execution_out_of_bounds() {
+        let packet = packet(CommandCode::Cp437Data, [55, 20, 2, 1], b"ab");
+        let diagnosis = diagnose(SOURCE, &packet, &Problem::Execution);
+        assert_eq!(
+            first_marks(&diagnosis),
+            "              ^^ ^^ ^^ ^^ ^^ ^^ ^^ ^^"
+        );
+        assert_eq!(
+            hints(&diagnosis),
+            [
+                "the origin is out of range: x 55 plus width 2 is more than the 56 tiles of the display",
+                "the origin is out of range: y 20 plus height 1 is more than the 20 tiles of the display",
+            ]
+        );
+    }
+
+    #[test]
+    fn long_dump_is_cut_off() {
+        let mut payload = vec![0; 200];
+        payload[150] = 0xff;
+        let packet = packet(CommandCode::Utf8Data, [0, 0, 50, 4], &payload);
+        let diagnosis = diagnose_decode(&packet);
+        let dump = diagnosis
+            .lines()
+            .skip_while(|line| *line != "hex dump:")
+            .skip(1)
+            .take_while(|line| !line.starts_with("hint: "))
+            .collect::<Vec<_>>();
+        // four rows of context, the row with the invalid byte and its marks
+        assert_eq!(dump.len(), 4 + 2 + 1 + 1, "{dump:#?}");
+        assert_eq!(dump[4], "  ...");
+        assert!(dump[5].starts_with("  00a0  "));
+        assert!(dump[6].ends_with("^^"));
+        assert_eq!(dump[7], "  ...");
+    }
+}
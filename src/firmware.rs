@@ -0,0 +1,157 @@
+use crate::{command_kind::CommandKind, screensaver::Screensaver};
+use clap::ValueEnum;
+use log::{info, warn};
+use servicepoint::{
+    Brightness, ClearCommand, Cp437Grid, Cp437GridCommand,
+    GlobalBrightnessCommand, Grid, Origin, TypedCommand, TILE_HEIGHT,
+    TILE_WIDTH,
+};
+use servicepoint_simulator::{ExecutionResult, Simulator};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+// CP437 box drawing characters for the frame around the splash screen
+const FRAME_TOP_LEFT: u8 = 0xC9;
+const FRAME_TOP_RIGHT: u8 = 0xBB;
+const FRAME_BOTTOM_LEFT: u8 = 0xC8;
+const FRAME_BOTTOM_RIGHT: u8 = 0xBC;
+const FRAME_HORIZONTAL: u8 = 0xCD;
+const FRAME_VERTICAL: u8 = 0xBA;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HardResetMode {
+    /// stop the simulator
+    #[default]
+    Exit,
+    /// reset the display and boot again
+    Restart,
+}
+
+/// What the simulated display firmware does on startup, on a hard reset and when idle.
+#[derive(Debug, Default)]
+pub struct Firmware {
+    pub hard_reset: HardResetMode,
+    pub boot_splash: bool,
+    pub screensaver: Option<Screensaver>,
+    /// reject packets that decode, but deviate from the protocol
+    pub strict: bool,
+    /// only show changes after a command of this kind, instead of after every command
+    pub frame_marker: Option<CommandKind>,
+}
+
+impl Firmware {
+    /// Resets the display and shows the splash screen, if enabled.
+    pub fn boot(&self, simulator: &Simulator, bound: SocketAddr) {
+        let _ = simulator.execute(TypedCommand::Clear(ClearCommand));
+        let _ = simulator.execute(
+            GlobalBrightnessCommand {
+                brightness: Brightness::MAX,
+            }
+            .into(),
+        );
+        if !self.boot_splash {
+            return;
+        }
+
+        let address = reachable_address(bound);
+        info!("showing boot splash for {address}");
+        let command = Cp437GridCommand {
+            grid: splash(address),
+            origin: Origin::ZERO,
+        };
+        if simulator.execute(command.into()) != ExecutionResult::Success {
+            warn!("could not show boot splash");
+        }
+    }
+
+    /// Decides what happens with the result of a hard reset.
+    ///
+    /// Returns [ExecutionResult::Shutdown] if the simulator should stop.
+    pub fn hard_reset(
+        &self,
+        simulator: &Simulator,
+        bound: SocketAddr,
+    ) -> ExecutionResult {
+        match self.hard_reset {
+            HardResetMode::Exit => ExecutionResult::Shutdown,
+            HardResetMode::Restart => {
+                warn!("display restarting");
+                self.boot(simulator, bound);
+                ExecutionResult::Success
+            }
+        }
+    }
+}
+
+fn splash(address: SocketAddr) -> Cp437Grid {
+    let mut grid = Cp437Grid::new(TILE_WIDTH, TILE_HEIGHT);
+    let (right, bottom) = (TILE_WIDTH - 1, TILE_HEIGHT - 1);
+    for x in 1..right {
+        grid.set(x, 0, FRAME_HORIZONTAL);
+        grid.set(x, bottom, FRAME_HORIZONTAL);
+    }
+    for y in 1..bottom {
+        grid.set(0, y, FRAME_VERTICAL);
+        grid.set(right, y, FRAME_VERTICAL);
+    }
+    grid.set(0, 0, FRAME_TOP_LEFT);
+    grid.set(right, 0, FRAME_TOP_RIGHT);
+    grid.set(0, bottom, FRAME_BOTTOM_LEFT);
+    grid.set(right, bottom, FRAME_BOTTOM_RIGHT);
+
+    let title = [
+        String::from("SERVICE POINT DISPLAY"),
+        format!("simulator v{}", env!("CARGO_PKG_VERSION")),
+    ];
+    for (text, y) in title.iter().zip([6, 8]) {
+        write_text(&mut grid, centered_start(text.len()), y, text);
+    }
+
+    // aligned with each other instead of centered on their own
+    let details = [
+        format!("IP   {}", address.ip()),
+        format!("PORT {}", address.port()),
+    ];
+    let width = details.iter().map(String::len).max().unwrap_or_default();
+    for (text, y) in details.iter().zip([11, 12]) {
+        write_text(&mut grid, centered_start(width), y, text);
+    }
+    grid
+}
+
+fn centered_start(width: usize) -> usize {
+    TILE_WIDTH.saturating_sub(width) / 2
+}
+
+/// Writes ASCII text into the grid, cut off at the frame.
+fn write_text(grid: &mut Cp437Grid, start: usize, y: usize, text: &str) {
+    let start = start.max(1);
+    for (x, char) in (start..TILE_WIDTH - 1).zip(text.bytes()) {
+        grid.set(x, y, char);
+    }
+}
+
+/// Finds the address other hosts can send packets to, even if the socket is bound to all interfaces.
+fn reachable_address(bound: SocketAddr) -> SocketAddr {
+    if !bound.ip().is_unspecified() {
+        return bound;
+    }
+    let (unspecified, remote) = match bound.ip() {
+        IpAddr::V4(_) => (
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+        ),
+        IpAddr::V6(_) => (
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        ),
+    };
+    // connecting a UDP socket does not send anything, but picks the interface
+    // that would be used to reach the remote address
+    UdpSocket::bind((unspecified, 0))
+        .and_then(|socket| {
+            socket.connect((remote, 9))?;
+            socket.local_addr()
+        })
+        .map(|local| SocketAddr::new(local.ip(), bound.port()))
+        .unwrap_or(bound)
+}
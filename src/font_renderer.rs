@@ -13,7 +13,7 @@ use pathfinder_geometry::{
     vector::{vec2f, vec2i},
 };
 use servicepoint::{Bitmap, Grid, Origin, Pixels, TILE_SIZE};
-use std::sync::{Mutex, MutexGuard};
+use std::sync::Mutex;
 
 #[derive(Debug)]
 struct SendFont(Font);
@@ -27,10 +27,16 @@ impl AsRef<Font> for SendFont {
     }
 }
 
+/// The font and the canvas it is rasterized onto, which are only used while holding the lock.
 #[derive(Debug)]
-pub struct FontRenderer8x8 {
+struct Rasterizer {
     font: SendFont,
-    canvas: Mutex<Canvas>,
+    canvas: Canvas,
+}
+
+#[derive(Debug)]
+pub struct FontRenderer8x8 {
+    rasterizer: Mutex<Rasterizer>,
     fallback_char: Option<u32>,
 }
 
@@ -53,9 +59,11 @@ impl FontRenderer8x8 {
         assert_eq!(canvas.stride, TILE_SIZE);
         let fallback_char = font.glyph_for_char(Self::FALLBACK_CHAR);
         Self {
-            font: SendFont(font),
+            rasterizer: Mutex::new(Rasterizer {
+                font: SendFont(font),
+                canvas,
+            }),
             fallback_char,
-            canvas: Mutex::new(canvas),
         }
     }
 
@@ -77,12 +85,13 @@ impl FontRenderer8x8 {
         bitmap: &mut Bitmap,
         offset: Origin<Pixels>,
     ) -> Result<(), RenderError> {
-        let glyph_id = self.get_glyph(char)?;
+        let mut rasterizer = self.rasterizer.lock().unwrap();
+        let Rasterizer { font, canvas } = &mut *rasterizer;
+        let glyph_id = self.get_glyph(font.as_ref(), char)?;
 
-        let mut canvas = self.canvas.lock().unwrap();
         canvas.pixels.fill(0);
-        self.font.as_ref().rasterize_glyph(
-            &mut canvas,
+        font.as_ref().rasterize_glyph(
+            canvas,
             glyph_id,
             TILE_SIZE as f32,
             Transform2F::from_translation(vec2f(0f32, TILE_SIZE as f32))
@@ -95,7 +104,7 @@ impl FontRenderer8x8 {
     }
 
     fn copy_to_bitmap(
-        canvas: MutexGuard<Canvas>,
+        canvas: &Canvas,
         bitmap: &mut Bitmap,
         offset: Origin<Pixels>,
     ) -> Result<(), RenderError> {
@@ -112,10 +121,8 @@ impl FontRenderer8x8 {
         Ok(())
     }
 
-    fn get_glyph(&self, char: char) -> Result<u32, RenderError> {
-        self.font
-            .as_ref()
-            .glyph_for_char(char)
+    fn get_glyph(&self, font: &Font, char: char) -> Result<u32, RenderError> {
+        font.glyph_for_char(char)
             .or(self.fallback_char)
             .ok_or(GlyphNotFound(char))
     }
@@ -131,3 +138,43 @@ impl Default for FontRenderer8x8 {
         FontRenderer8x8::new(utf8_font)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_from_multiple_threads() {
+        let renderer = FontRenderer8x8::default();
+        let bitmaps = std::thread::scope(|scope| {
+            let threads = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut bitmap =
+                            Bitmap::new(TILE_SIZE, TILE_SIZE).unwrap();
+                        renderer
+                            .render('W', &mut bitmap, Origin::ZERO)
+                            .unwrap();
+                        bitmap
+                    })
+                })
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        assert!(bitmaps[0].iter().any(|pixel| *pixel));
+        assert!(bitmaps.iter().all(|bitmap| *bitmap == bitmaps[0]));
+    }
+
+    #[test]
+    fn render_out_of_bounds() {
+        let renderer = FontRenderer8x8::default();
+        let mut bitmap = Bitmap::new(TILE_SIZE, TILE_SIZE).unwrap();
+        let result =
+            renderer.render('W', &mut bitmap, Origin::new(TILE_SIZE, 0));
+        assert!(matches!(result, Err(OutOfBounds(0, 0))));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::defects::Defects;
+use servicepoint_simulator::snapshot::Snapshot;
+use std::{
+    sync::{Arc, Condvar, Mutex, RwLock, RwLockReadGuard},
+    time::Duration,
+};
+
+/// The display state that is shown, which is only replaced between commands.
+///
+/// Commands are executed on the back buffer, the display of a
+/// [servicepoint_simulator::Simulator].
+/// Readers of the front buffer never see a half-applied command.
+/// Simulated hardware defects are applied to every presented state.
+#[derive(Debug)]
+pub struct FrontBuffer {
+    state: RwLock<Snapshot>,
+    defects: Option<Arc<Defects>>,
+    /// counts the presented states, so waiting readers can tell whether they missed one
+    presented: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl FrontBuffer {
+    /// Creates a front buffer showing the current state of the back buffer,
+    /// on a display with the simulated defects if there are any.
+    pub fn new(state: Snapshot, defects: Option<Arc<Defects>>) -> Self {
+        let state = match &defects {
+            Some(defects) => defects.damage(state.clone(), &state),
+            None => state,
+        };
+        Self {
+            state: RwLock::new(state),
+            defects,
+            presented: Mutex::new(0),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Shows the state of the back buffer, which has to be at a command boundary.
+    pub fn present(&self, state: Snapshot) {
+        let mut shown = self.state.write().unwrap();
+        *shown = match &self.defects {
+            Some(defects) => defects.damage(state, &shown),
+            None => state,
+        };
+        drop(shown);
+        *self.presented.lock().unwrap() += 1;
+        self.changed.notify_all();
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, Snapshot> {
+        self.state.read().unwrap()
+    }
+
+    /// The number of states presented so far.
+    pub fn presented(&self) -> u64 {
+        *self.presented.lock().unwrap()
+    }
+
+    /// Waits until more than `seen` states were presented or the timeout elapsed.
+    ///
+    /// Returns the number of states presented so far.
+    pub fn wait_for_present(&self, seen: u64, timeout: Duration) -> u64 {
+        let presented = self.presented.lock().unwrap();
+        let (presented, _) = self
+            .changed
+            .wait_timeout_while(presented, timeout, |presented| {
+                *presented == seen
+            })
+            .unwrap();
+        *presented
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use servicepoint::{
+        Bitmap, Brightness, BrightnessGrid, Grid, TILE_HEIGHT, TILE_WIDTH,
+    };
+
+    fn state(is_on: bool) -> Snapshot {
+        let mut bitmap = Bitmap::max_sized();
+        bitmap.fill(is_on);
+        Snapshot::new(bitmap, BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT))
+            .unwrap()
+    }
+
+    #[test]
+    fn frozen_tile_keeps_first_state() {
+        let defects = Defects::parse("frozen-tile 0 0\ndead 9 0").unwrap();
+        let front = FrontBuffer::new(state(false), Some(Arc::new(defects)));
+        front.present(state(true));
+        front.present(state(true));
+        let shown = front.read();
+        assert!(!shown.bitmap().get(0, 0));
+        assert!(!shown.bitmap().get(9, 0));
+        assert!(shown.bitmap().get(8, 0));
+    }
+
+    /// A state that can be recognized as a whole: all pixels are on exactly if the brightness is odd.
+    fn numbered_state(number: u8) -> Snapshot {
+        let brightness = Brightness::saturating_from(number % 12);
+        let mut bitmap = Bitmap::max_sized();
+        bitmap.fill(u8::from(brightness) % 2 == 1);
+        let mut luma = BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT);
+        luma.fill(brightness);
+        Snapshot::new(bitmap, luma).unwrap()
+    }
+
+    #[test]
+    fn readers_only_see_complete_states() {
+        const PRESENTS: u8 = 200;
+        let front = FrontBuffer::new(numbered_state(0), None);
+        std::thread::scope(|scope| {
+            let readers = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut seen = 0;
+                        while seen < u64::from(PRESENTS) {
+                            seen = front
+                                .wait_for_present(seen, Duration::from_secs(1));
+                            let state = front.read();
+                            let brightness = state.brightness().get(0, 0);
+                            assert!(state
+                                .brightness()
+                                .iter()
+                                .all(|tile| *tile == brightness));
+                            let is_on = u8::from(brightness) % 2 == 1;
+                            assert!(state
+                                .bitmap()
+                                .iter()
+                                .all(|pixel| *pixel == is_on));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for number in 1..=PRESENTS {
+                front.present(numbered_state(number));
+            }
+            for reader in readers {
+                reader.join().unwrap();
+            }
+        });
+        assert_eq!(front.presented(), u64::from(PRESENTS));
+        assert_eq!(*front.read(), numbered_state(PRESENTS));
+    }
+}
@@ -1,21 +1,47 @@
-use log::{info, warn};
+use log::{error, info, warn};
 use servicepoint::*;
-use std::{sync::mpsc::Sender, sync::RwLock};
+use servicepoint_simulator::{snapshot::Snapshot, Simulator};
+use std::{
+    fs,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use winit::{
-    application::ApplicationHandler, dpi::LogicalSize, event::WindowEvent,
-    event_loop::ActiveEventLoop, keyboard::KeyCode::KeyC, window::WindowId,
+    application::ApplicationHandler,
+    dpi::{LogicalSize, PhysicalPosition},
+    event::WindowEvent,
+    event_loop::{ActiveEventLoop, ControlFlow},
+    keyboard::KeyCode::{KeyC, KeyG, KeyI, KeyL, KeyS, Tab},
+    window::WindowId,
 };
 
-use crate::cli::GuiOptions;
-use crate::gui_window::GuiWindow;
+use crate::cli::{ClientMode, GuiOptions};
+use crate::front_buffer::FrontBuffer;
+use crate::gui_window::{GuiWindow, WINDOW_TITLE};
+use crate::metrics::METRICS;
+use crate::sender_displays::{SenderDisplay, SenderDisplays};
+use crate::udp_server::StopHandle;
 
 pub struct Gui<'t> {
-    display: &'t RwLock<Bitmap>,
-    luma: &'t RwLock<BrightnessGrid>,
-    stop_udp_tx: Sender<()>,
+    simulator: &'t Simulator,
+    front: &'t FrontBuffer,
+    senders: Option<&'t SenderDisplays>,
+    /// the sender chosen with Tab, which keeps being shown when others are forgotten
+    selected_sender: Option<IpAddr>,
+    composited: bool,
+    stop_udp: StopHandle,
     options: GuiOptions,
     logical_size: LogicalSize<u16>,
     window: Option<GuiWindow>,
+    hovered_pixel: Option<(usize, usize)>,
+    title: String,
+    /// the shortest time between two frames, from the maximum frame rate
+    frame_interval: Duration,
+    last_frame: Instant,
+    /// when the next frame is drawn because packets were handled
+    next_frame: Option<Instant>,
 }
 
 const SPACER_HEIGHT: usize = 4;
@@ -24,33 +50,92 @@ const PIXEL_HEIGHT_WITH_SPACERS: usize =
     PIXEL_HEIGHT + NUM_SPACERS * SPACER_HEIGHT;
 
 const OFF_COLOR: u32 = u32::from_ne_bytes([0u8, 0, 0, 0]);
+const GRID_COLOR: u32 = u32::from_ne_bytes([0x30u8, 0x30, 0x30, 0]);
+
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_SUFFIX: &str = ".txt";
 
 #[derive(Debug)]
 pub enum AppEvents {
-    UdpPacketHandled,
+    /// The display changed, e.g. because the contained number of packets was executed.
+    UdpPacketsHandled(usize),
     UdpThreadClosed,
 }
 
 impl<'t> Gui<'t> {
     pub fn new(
-        display: &'t RwLock<Bitmap>,
-        luma: &'t RwLock<BrightnessGrid>,
-        stop_udp_tx: Sender<()>,
+        simulator: &'t Simulator,
+        front: &'t FrontBuffer,
+        senders: Option<&'t SenderDisplays>,
+        client_mode: ClientMode,
+        stop_udp: StopHandle,
         options: GuiOptions,
     ) -> Self {
         Self {
             window: None,
             logical_size: Self::get_logical_size(options.spacers),
-            display,
-            luma,
-            stop_udp_tx,
+            simulator,
+            front,
+            senders,
+            selected_sender: None,
+            composited: client_mode == ClientMode::Composited,
+            stop_udp,
+            hovered_pixel: None,
+            title: String::from(WINDOW_TITLE),
+            frame_interval: Duration::from_secs(1) / options.max_fps,
+            last_frame: Instant::now(),
+            next_frame: None,
             options,
         }
     }
 
+    /// The virtual display currently shown, if a single sender is selected.
+    ///
+    /// With isolated clients, the first sender is shown until another one is selected
+    /// or when the selected one was forgotten.
+    fn shown_sender(&self) -> Option<Arc<SenderDisplay>> {
+        let senders = self.senders?;
+        let selected =
+            self.selected_sender.and_then(|source| senders.get(source));
+        if selected.is_some() || self.composited {
+            return selected;
+        }
+        senders.get_index(0)
+    }
+
+    /// The display the commands for the selected display are executed on.
+    fn shown_simulator<'a>(
+        &self,
+        sender: &'a Option<Arc<SenderDisplay>>,
+    ) -> &'a Simulator
+    where
+        't: 'a,
+    {
+        match sender {
+            Some(sender) => &sender.simulator,
+            None => self.simulator,
+        }
+    }
+
+    /// The state shown for the selected display, which never contains a half-applied command.
+    fn shown_front<'a>(
+        &self,
+        sender: &'a Option<Arc<SenderDisplay>>,
+    ) -> &'a FrontBuffer
+    where
+        't: 'a,
+    {
+        match sender {
+            Some(sender) => &sender.front,
+            None => self.front,
+        }
+    }
+
     fn draw(&mut self) {
-        let display = self.display.read().unwrap();
-        let luma = self.luma.read().unwrap();
+        let sender = self.shown_sender();
+        // copy the state so the UDP thread does not have to wait while the frame is rendered
+        let (display, luma) =
+            self.shown_front(&sender).read().clone().into_parts();
         let brightness_scale =
             (u8::MAX as f32) / (u8::from(Brightness::MAX) as f32);
 
@@ -75,8 +160,13 @@ impl<'t> Gui<'t> {
                         Self::get_on_color(&self.options, brightness);
                     let start_x = tile_x * TILE_SIZE;
                     for x in start_x..start_x + TILE_SIZE {
-                        let color = if display.get(x, y) {
+                        let is_on = display.get(x, y);
+                        let color = if is_on {
                             on_color
+                        } else if self.options.grid
+                            && (x == start_x || y == start_y)
+                        {
+                            GRID_COLOR
                         } else {
                             OFF_COLOR
                         };
@@ -87,6 +177,145 @@ impl<'t> Gui<'t> {
         }
 
         buffer.present().unwrap();
+        self.last_frame = Instant::now();
+        // the hovered pixel or the list of senders may have changed with this frame
+        self.update_title();
+    }
+
+    fn update_title(&mut self) {
+        let mut title = String::from(WINDOW_TITLE);
+        let sender = self.shown_sender();
+        if let Some(senders) = self.senders {
+            let index = sender
+                .as_ref()
+                .and_then(|sender| senders.index_of(sender.source));
+            match (&sender, index) {
+                (Some(sender), Some(index)) => title.push_str(&format!(
+                    " - sender {}/{} {}",
+                    index + 1,
+                    senders.len(),
+                    sender.source
+                )),
+                _ if self.composited => title.push_str(&format!(
+                    " - composite of {} senders",
+                    senders.len()
+                )),
+                _ => title.push_str(" - waiting for senders"),
+            }
+        }
+
+        if let Some((x, y)) = self.hovered_pixel {
+            let state = self.shown_front(&sender).read();
+            let (tile_x, tile_y) = (x / TILE_SIZE, y / TILE_SIZE);
+            let is_set = state.bitmap().get(x, y);
+            let brightness = u8::from(state.brightness().get(tile_x, tile_y));
+            drop(state);
+            title.push_str(&format!(
+                " - pixel {x} {y} | tile {tile_x} {tile_y} | offset {} | {} | brightness {brightness}",
+                y * PIXEL_WIDTH + x,
+                if is_set { "on" } else { "off" },
+            ));
+        }
+
+        if title != self.title {
+            if let Some(window) = &self.window {
+                window.set_title(&title);
+            }
+            self.title = title;
+        }
+    }
+
+    /// Maps a cursor position inside the window to the display pixel below it.
+    ///
+    /// Returns `None` if the cursor is on a spacer or outside the display.
+    fn pixel_at(
+        &self,
+        position: PhysicalPosition<f64>,
+    ) -> Option<(usize, usize)> {
+        let inner_size = self.window.as_ref()?.inner_size();
+        if position.x < 0.0
+            || position.y < 0.0
+            || inner_size.width == 0
+            || inner_size.height == 0
+        {
+            return None;
+        }
+
+        let x = (position.x * self.logical_size.width as f64
+            / inner_size.width as f64) as usize;
+        let frame_y = (position.y * self.logical_size.height as f64
+            / inner_size.height as f64) as usize;
+
+        let y = if self.options.spacers {
+            let row_height = TILE_SIZE + SPACER_HEIGHT;
+            let (tile_y, row_y) = (frame_y / row_height, frame_y % row_height);
+            if row_y >= TILE_SIZE {
+                return None;
+            }
+            tile_y * TILE_SIZE + row_y
+        } else {
+            frame_y
+        };
+
+        (x < PIXEL_WIDTH && y < PIXEL_HEIGHT).then_some((x, y))
+    }
+
+    fn save_snapshot(&self) {
+        let sender = self.shown_sender();
+        let snapshot = self.shown_front(&sender).read().clone();
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = self
+            .options
+            .snapshot_dir
+            .join(format!("{SNAPSHOT_PREFIX}{millis}{SNAPSHOT_SUFFIX}"));
+        match snapshot.save(&path) {
+            Ok(()) => info!("saved snapshot to {}", path.display()),
+            Err(err) => error!("could not save {}: {err}", path.display()),
+        }
+    }
+
+    /// Replaces the shown display with the most recently saved snapshot.
+    fn load_snapshot(&self) {
+        let Some(path) = self.newest_snapshot() else {
+            warn!(
+                "no snapshot to load in {}",
+                self.options.snapshot_dir.display()
+            );
+            return;
+        };
+        let snapshot = match Snapshot::load(&path) {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                error!("could not load {}: {err}", path.display());
+                return;
+            }
+        };
+
+        let sender = self.shown_sender();
+        self.shown_simulator(&sender).restore(snapshot.clone());
+        self.shown_front(&sender).present(snapshot);
+        info!("loaded snapshot {}", path.display());
+    }
+
+    fn newest_snapshot(&self) -> Option<PathBuf> {
+        fs::read_dir(&self.options.snapshot_dir)
+            .ok()?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let millis = path
+                    .file_name()?
+                    .to_str()?
+                    .strip_prefix(SNAPSHOT_PREFIX)?
+                    .strip_suffix(SNAPSHOT_SUFFIX)?
+                    .parse::<u128>()
+                    .ok()?;
+                Some((millis, path))
+            })
+            .max_by_key(|(millis, _)| *millis)
+            .map(|(_, path)| path)
     }
 
     fn get_on_color(options: &GuiOptions, brightness: u8) -> u32 {
@@ -111,14 +340,16 @@ impl<'t> Gui<'t> {
 impl ApplicationHandler<AppEvents> for Gui<'_> {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         self.window = Some(GuiWindow::new(event_loop, self.logical_size));
+        self.title = String::from(WINDOW_TITLE);
     }
 
     fn user_event(&mut self, event_loop: &ActiveEventLoop, event: AppEvents) {
         match event {
-            AppEvents::UdpPacketHandled => {
-                if let Some(window) = &self.window {
-                    window.request_redraw();
-                }
+            AppEvents::UdpPacketsHandled(_) => {
+                // all changes until the next frame are drawn together
+                self.next_frame.get_or_insert_with(|| {
+                    Instant::now().max(self.last_frame + self.frame_interval)
+                });
             }
             AppEvents::UdpThreadClosed => {
                 info!("stopping ui thread after udp thread stopped");
@@ -136,7 +367,7 @@ impl ApplicationHandler<AppEvents> for Gui<'_> {
         match event {
             WindowEvent::CloseRequested => {
                 warn!("window event close requested");
-                let _ = self.stop_udp_tx.send(()); // try to stop udp thread
+                self.stop_udp.stop();
                 event_loop.exit();
             }
             WindowEvent::RedrawRequested => {
@@ -145,14 +376,103 @@ impl ApplicationHandler<AppEvents> for Gui<'_> {
             WindowEvent::KeyboardInput { event, .. }
                 if event.physical_key == KeyC && !event.repeat =>
             {
-                self.display.write().unwrap().fill(false);
-                self.luma.write().unwrap().fill(Brightness::MAX);
+                let sender = self.shown_sender();
+                let simulator = self.shown_simulator(&sender);
+                let _ = simulator.execute(TypedCommand::Clear(ClearCommand));
+                let _ = simulator.execute(
+                    GlobalBrightnessCommand {
+                        brightness: Brightness::MAX,
+                    }
+                    .into(),
+                );
+                self.shown_front(&sender).present(simulator.snapshot());
+                self.window.as_ref().unwrap().request_redraw();
+            }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.physical_key == Tab
+                    && event.state.is_pressed()
+                    && self.senders.is_some() =>
+            {
+                let senders = self.senders.map(SenderDisplays::all);
+                let senders = senders.unwrap_or_default();
+                let shown = self.shown_sender().and_then(|shown| {
+                    senders
+                        .iter()
+                        .position(|sender| Arc::ptr_eq(sender, &shown))
+                });
+                let next = match shown {
+                    Some(index) => senders.get(index + 1),
+                    None => senders.first(),
+                };
+                // the composite is shown before the first sender
+                self.selected_sender = match next {
+                    Some(sender) => Some(sender.source),
+                    None if self.composited => None,
+                    None => senders.first().map(|sender| sender.source),
+                };
+                self.window.as_ref().unwrap().request_redraw();
+            }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.physical_key == KeyG
+                    && event.state.is_pressed()
+                    && !event.repeat =>
+            {
+                self.options.grid = !self.options.grid;
+                self.window.as_ref().unwrap().request_redraw();
+            }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.physical_key == KeyS
+                    && event.state.is_pressed()
+                    && !event.repeat =>
+            {
+                self.save_snapshot();
+            }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.physical_key == KeyL
+                    && event.state.is_pressed()
+                    && !event.repeat =>
+            {
+                self.load_snapshot();
                 self.window.as_ref().unwrap().request_redraw();
             }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.physical_key == KeyI
+                    && event.state.is_pressed()
+                    && !event.repeat =>
+            {
+                info!("{}", METRICS.compression_report());
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                let hovered_pixel = self.pixel_at(position);
+                if hovered_pixel != self.hovered_pixel {
+                    self.hovered_pixel = hovered_pixel;
+                    self.update_title();
+                }
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.hovered_pixel = None;
+                self.update_title();
+            }
             _ => {}
         }
     }
 
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        match self.next_frame {
+            Some(next_frame) if next_frame <= Instant::now() => {
+                self.next_frame = None;
+                if let Some(window) = &self.window {
+                    window.request_redraw();
+                }
+                event_loop.set_control_flow(ControlFlow::Wait);
+            }
+            Some(next_frame) => {
+                event_loop.set_control_flow(ControlFlow::WaitUntil(next_frame));
+            }
+            None => event_loop.set_control_flow(ControlFlow::Wait),
+        }
+    }
+
     fn suspended(&mut self, _: &ActiveEventLoop) {
         self.window = None;
     }
@@ -1,10 +1,16 @@
 use softbuffer::Buffer;
 use std::{num::NonZero, rc::Rc};
-use winit::{dpi::LogicalSize, event_loop::ActiveEventLoop, window::Window};
+use winit::{
+    dpi::{LogicalSize, PhysicalSize},
+    event_loop::ActiveEventLoop,
+    window::Window,
+};
 
 type Context = softbuffer::Context<Rc<Window>>;
 type Surface = softbuffer::Surface<Rc<Window>, Rc<Window>>;
 
+pub const WINDOW_TITLE: &str = "servicepoint-simulator";
+
 pub struct GuiWindow {
     winit_window: Rc<Window>,
     surface: Surface,
@@ -16,7 +22,7 @@ impl GuiWindow {
         logical_size: LogicalSize<u16>,
     ) -> GuiWindow {
         let attributes = Window::default_attributes()
-            .with_title("servicepoint-simulator")
+            .with_title(WINDOW_TITLE)
             .with_min_inner_size(logical_size)
             .with_inner_size(logical_size)
             .with_transparent(false);
@@ -37,10 +43,18 @@ impl GuiWindow {
         }
     }
 
-    pub fn get_buffer(&mut self) -> Buffer<Rc<Window>, Rc<Window>> {
+    pub fn get_buffer(&mut self) -> Buffer<'_, Rc<Window>, Rc<Window>> {
         self.surface.buffer_mut().unwrap()
     }
     pub(crate) fn request_redraw(&self) {
         self.winit_window.request_redraw();
     }
+
+    pub(crate) fn set_title(&self, title: &str) {
+        self.winit_window.set_title(title);
+    }
+
+    pub(crate) fn inner_size(&self) -> PhysicalSize<u32> {
+        self.winit_window.inner_size()
+    }
 }
@@ -0,0 +1,294 @@
+use crate::command_kind::CommandKind;
+use clap::ValueEnum;
+use log::{info, warn};
+use servicepoint::FRAME_PACING;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the display is busy after processing a command of a kind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommandCost {
+    pub kind: CommandKind,
+    pub cost: Duration,
+}
+
+/// Emulates the limited throughput of the real display.
+///
+/// Received packets wait in a bounded queue for the configured latency. The
+/// display then processes one packet at a time, and is busy for the cost of
+/// the command afterward. Packets arriving while the queue is full are dropped.
+#[derive(Debug)]
+pub struct HardwareEmulation {
+    costs: Vec<CommandCost>,
+    latency: Duration,
+    queue_size: usize,
+    queue: VecDeque<(Instant, Vec<u8>, SocketAddr)>,
+    busy_until: Instant,
+    processed: usize,
+    dropped: usize,
+    last_stats_log: Instant,
+}
+
+impl CommandCost {
+    /// Rough estimates for the real display. Use `--command-cost` to tune them.
+    fn default_for(kind: CommandKind) -> Duration {
+        match kind {
+            CommandKind::Bitmap
+            | CommandKind::BitVec
+            | CommandKind::BitmapLegacy => FRAME_PACING,
+            CommandKind::CharGrid | CommandKind::Cp437Grid => {
+                Duration::from_millis(20)
+            }
+            CommandKind::BrightnessGrid => Duration::from_millis(10),
+            CommandKind::Clear
+            | CommandKind::Brightness
+            | CommandKind::FadeOut => Duration::from_millis(5),
+            CommandKind::HardReset => Duration::ZERO,
+        }
+    }
+}
+
+impl FromStr for CommandCost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, millis) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <COMMAND>=<MILLIS>, got '{s}'"))?;
+        let millis = millis
+            .parse()
+            .map_err(|e| format!("invalid cost '{millis}': {e}"))?;
+        Ok(CommandCost {
+            kind: CommandKind::from_str(kind, true)?,
+            cost: Duration::from_millis(millis),
+        })
+    }
+}
+
+impl HardwareEmulation {
+    pub fn new(
+        costs: Vec<CommandCost>,
+        latency: Duration,
+        queue_size: usize,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            costs,
+            latency,
+            queue_size,
+            queue: VecDeque::with_capacity(queue_size),
+            busy_until: now,
+            processed: 0,
+            dropped: 0,
+            last_stats_log: now,
+        }
+    }
+
+    /// Puts a received packet into the queue, or drops it if the queue is full.
+    ///
+    /// Returns the dropped packet.
+    pub fn enqueue(
+        &mut self,
+        packet: Vec<u8>,
+        source: SocketAddr,
+    ) -> Result<(), Vec<u8>> {
+        self.enqueue_at(packet, source, Instant::now())
+    }
+
+    fn enqueue_at(
+        &mut self,
+        packet: Vec<u8>,
+        source: SocketAddr,
+        now: Instant,
+    ) -> Result<(), Vec<u8>> {
+        let result = if self.queue.len() >= self.queue_size {
+            self.dropped += 1;
+            Err(packet)
+        } else {
+            self.queue.push_back((now, packet, source));
+            Ok(())
+        };
+        self.log_stats();
+        result
+    }
+
+    /// Returns the next packet if the display is ready to process it.
+    pub fn dequeue(&mut self) -> Option<(Vec<u8>, SocketAddr)> {
+        self.dequeue_at(Instant::now())
+    }
+
+    fn dequeue_at(&mut self, now: Instant) -> Option<(Vec<u8>, SocketAddr)> {
+        self.log_stats();
+        let (received, _, _) = self.queue.front()?;
+        if now < self.busy_until || now < *received + self.latency {
+            return None;
+        }
+        self.processed += 1;
+        self.queue
+            .pop_front()
+            .map(|(_, packet, source)| (packet, source))
+    }
+
+    /// When the next queued packet can be processed, if there is one.
+    pub fn next_ready(&self) -> Option<Instant> {
+        let (received, _, _) = self.queue.front()?;
+        Some(self.busy_until.max(*received + self.latency))
+    }
+
+    /// Marks the display as busy for the duration of the command.
+    pub fn charge(&mut self, kind: CommandKind) {
+        self.charge_at(kind, Instant::now());
+    }
+
+    fn charge_at(&mut self, kind: CommandKind, now: Instant) {
+        let cost = self
+            .costs
+            .iter()
+            .rev()
+            .find(|cost| cost.kind == kind)
+            .map_or_else(|| CommandCost::default_for(kind), |cost| cost.cost);
+        self.busy_until = now + cost;
+    }
+
+    fn log_stats(&mut self) {
+        let elapsed = self.last_stats_log.elapsed();
+        if elapsed < STATS_LOG_INTERVAL {
+            return;
+        }
+        if self.processed + self.dropped > 0 {
+            let per_second = self.processed as f64 / elapsed.as_secs_f64();
+            info!("processed {} packets ({per_second:.1}/s)", self.processed);
+        }
+        if self.dropped > 0 {
+            warn!(
+                "dropped {} packets in the last {}s because the receive queue was full",
+                self.dropped,
+                elapsed.as_secs()
+            );
+        }
+        self.processed = 0;
+        self.dropped = 0;
+        self.last_stats_log = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    const SOURCE: SocketAddr =
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2342));
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn packets_wait_for_latency() {
+        let mut hardware = HardwareEmulation::new(Vec::new(), millis(50), 16);
+        let start = Instant::now();
+        hardware.enqueue_at(vec![1], SOURCE, start).unwrap();
+        hardware
+            .enqueue_at(vec![2], SOURCE, start + millis(20))
+            .unwrap();
+
+        assert_eq!(hardware.next_ready(), Some(start + millis(50)));
+        assert_eq!(hardware.dequeue_at(start + millis(49)), None);
+        assert_eq!(
+            hardware.dequeue_at(start + millis(50)),
+            Some((vec![1], SOURCE))
+        );
+
+        // each packet waits for the latency after it was received
+        assert_eq!(hardware.next_ready(), Some(start + millis(70)));
+        assert_eq!(hardware.dequeue_at(start + millis(60)), None);
+        assert_eq!(
+            hardware.dequeue_at(start + millis(70)),
+            Some((vec![2], SOURCE))
+        );
+        assert_eq!(hardware.next_ready(), None);
+    }
+
+    #[test]
+    fn display_is_busy_for_cost_of_command() {
+        let costs = vec![CommandCost {
+            kind: CommandKind::Clear,
+            cost: millis(10),
+        }];
+        let mut hardware = HardwareEmulation::new(costs, Duration::ZERO, 16);
+        let start = Instant::now();
+        for number in 0..3 {
+            hardware.enqueue_at(vec![number], SOURCE, start).unwrap();
+        }
+
+        // one clear every 10ms, however many are waiting
+        let mut now = start;
+        for number in 0..3 {
+            assert_eq!(hardware.dequeue_at(now), Some((vec![number], SOURCE)));
+            hardware.charge_at(CommandKind::Clear, now);
+            assert_eq!(hardware.dequeue_at(now + millis(9)), None);
+            now += millis(10);
+            if number < 2 {
+                assert_eq!(hardware.next_ready(), Some(now));
+            }
+        }
+
+        // commands without a configured cost use the default
+        hardware.enqueue_at(vec![3], SOURCE, now).unwrap();
+        hardware.charge_at(CommandKind::Bitmap, now);
+        assert_eq!(hardware.next_ready(), Some(now + FRAME_PACING));
+    }
+
+    #[test]
+    fn later_costs_take_precedence() {
+        let costs = ["clear=10", "Clear=30"]
+            .map(|cost| cost.parse().unwrap())
+            .to_vec();
+        let mut hardware = HardwareEmulation::new(costs, Duration::ZERO, 16);
+        let start = Instant::now();
+        hardware.enqueue_at(vec![1], SOURCE, start).unwrap();
+
+        hardware.charge_at(CommandKind::Clear, start);
+
+        assert_eq!(hardware.next_ready(), Some(start + millis(30)));
+    }
+
+    #[test]
+    fn full_queue_drops_packets() {
+        let mut hardware =
+            HardwareEmulation::new(Vec::new(), Duration::ZERO, 2);
+        let start = Instant::now();
+        hardware.enqueue_at(vec![1], SOURCE, start).unwrap();
+        hardware.enqueue_at(vec![2], SOURCE, start).unwrap();
+
+        assert_eq!(hardware.enqueue_at(vec![3], SOURCE, start), Err(vec![3]));
+        assert_eq!(hardware.dropped, 1);
+
+        // processing a packet makes room for the next one
+        assert_eq!(hardware.dequeue_at(start), Some((vec![1], SOURCE)));
+        hardware.enqueue_at(vec![4], SOURCE, start).unwrap();
+        assert_eq!(hardware.dequeue_at(start), Some((vec![2], SOURCE)));
+        assert_eq!(hardware.dequeue_at(start), Some((vec![4], SOURCE)));
+    }
+
+    #[test]
+    fn parse_command_cost() {
+        assert_eq!(
+            "bitmap=40".parse(),
+            Ok(CommandCost {
+                kind: CommandKind::Bitmap,
+                cost: millis(40),
+            })
+        );
+        for invalid in ["bitmap", "bitmap=", "bitmap=-1", "unknown=5", "=5"] {
+            assert!(invalid.parse::<CommandCost>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,14 @@
+//! Simulates the Service Point display, so clients can be developed and tested without the real one.
+//!
+//! Use [Simulator] to execute commands in memory, e.g. in the tests of a client.
+
+#![deny(clippy::all)]
+
+pub mod command_executor;
+pub mod cp437_font;
+pub mod font_renderer;
+mod simulator;
+pub mod snapshot;
+
+pub use command_executor::ExecutionResult;
+pub use simulator::{FeedPacketError, Simulator};
@@ -0,0 +1,187 @@
+use crate::{
+    cli::LocalInputOptions,
+    udp_server::{PacketQueue, SocketError},
+};
+use log::{info, warn};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{fs::FileTypeExt, net::UnixDatagram},
+    path::{Path, PathBuf},
+};
+use std::{
+    io::{ErrorKind, Read},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+/// Packets read from stdin appear to be sent from this address, e.g. in logs and source filters.
+pub const STDIN_SOURCE: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+/// Packets received on the Unix socket appear to be sent from this address,
+/// as senders usually do not bind their socket to a path.
+#[cfg(unix)]
+pub const UNIX_SOURCE: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1);
+
+/// Receives packets from local tools, without binding a UDP port.
+#[derive(Debug, Default)]
+pub struct LocalInput {
+    #[cfg(unix)]
+    unix: Option<UnixSocket>,
+    stdin: bool,
+}
+
+/// A bound Unix datagram socket, which removes its file when dropped.
+#[cfg(unix)]
+#[derive(Debug)]
+struct UnixSocket {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+impl LocalInput {
+    /// Creates the Unix socket, reading stdin only starts with [Self::read_stdin].
+    pub fn open(options: &LocalInputOptions) -> Result<Self, SocketError> {
+        Ok(Self {
+            #[cfg(unix)]
+            unix: options.unix.as_deref().map(UnixSocket::bind).transpose()?,
+            stdin: options.stdin,
+        })
+    }
+
+    #[cfg(unix)]
+    pub fn unix_socket(&self) -> Option<&UnixDatagram> {
+        self.unix.as_ref().map(|unix| &unix.socket)
+    }
+
+    /// Starts reading packets from stdin into the queue, if requested.
+    pub fn read_stdin(&self, queue: PacketQueue) {
+        if self.stdin {
+            std::thread::spawn(move || {
+                read_packets(std::io::stdin().lock(), |packet| {
+                    queue.push(packet, STDIN_SOURCE)
+                })
+            });
+        }
+    }
+
+    /// Receives a pending packet from the Unix socket without blocking.
+    pub fn receive(&self, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        #[cfg(unix)]
+        if let Some(unix) = &self.unix {
+            match unix.socket.recv(buf) {
+                Ok(amount) => return Some((amount, UNIX_SOURCE)),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => warn!(
+                    "could not receive packet on {}: {err}",
+                    unix.path.display()
+                ),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+    fn bind(path: &Path) -> Result<Self, SocketError> {
+        let error = |err| SocketError::BindUnix(path.to_path_buf(), err);
+        // a socket left behind by a previous run would make binding fail
+        if fs::symlink_metadata(path)
+            .is_ok_and(|metadata| metadata.file_type().is_socket())
+        {
+            fs::remove_file(path).map_err(error)?;
+        }
+        let socket = UnixDatagram::bind(path).map_err(error)?;
+        socket.set_nonblocking(true).map_err(error)?;
+        info!("receiving packets on {}", path.display());
+        Ok(Self {
+            socket,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Reads length-prefixed packets from stdin until it is closed or `push` returns false.
+fn read_packets(mut stdin: impl Read, mut push: impl FnMut(Vec<u8>) -> bool) {
+    loop {
+        let mut length = [0; 2];
+        match stdin.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                info!("stdin was closed, no more packets will be read from it");
+                return;
+            }
+            Err(err) => {
+                warn!("could not read packet length from stdin: {err}");
+                return;
+            }
+        }
+
+        let mut packet = vec![0; u16::from_be_bytes(length) as usize];
+        if let Err(err) = stdin.read_exact(&mut packet) {
+            warn!("stdin ended in the middle of a packet: {err}");
+            return;
+        }
+        if !push(packet) {
+            // the server stopped
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(input: &[u8]) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        read_packets(input, |packet| {
+            packets.push(packet);
+            true
+        });
+        packets
+    }
+
+    #[test]
+    fn reads_packets_until_eof() {
+        let input = [0, 3, 1, 2, 3, 0, 0, 0, 1, 4];
+        assert_eq!(read_all(&input), [vec![1, 2, 3], vec![], vec![4]]);
+        assert!(read_all(&[]).is_empty());
+    }
+
+    #[test]
+    fn drops_truncated_packet() {
+        // the second packet announces 5 bytes, but only 2 follow
+        let input = [0, 1, 9, 0, 5, 1, 2];
+        assert_eq!(read_all(&input), [vec![9]]);
+        // the length of the second packet is cut off
+        let input = [0, 1, 9, 0];
+        assert_eq!(read_all(&input), [vec![9]]);
+    }
+
+    #[test]
+    fn long_packet_length_is_big_endian() {
+        let mut input = vec![0x01, 0x02];
+        input.extend(vec![7; 0x0102]);
+        assert_eq!(read_all(&input), [vec![7; 0x0102]]);
+    }
+
+    #[test]
+    fn stops_when_push_fails() {
+        let input = [0, 1, 1, 0, 1, 2];
+        let mut pushed = 0;
+        read_packets(&input[..], |_| {
+            pushed += 1;
+            false
+        });
+        assert_eq!(pushed, 1);
+    }
+}
@@ -1,52 +1,201 @@
 #![deny(clippy::all)]
 
-use crate::font_renderer::FontRenderer8x8;
+use crate::compositor::Compositor;
+use crate::diagnostics::DIAGNOSTICS_LOG_TARGET;
+use crate::firmware::Firmware;
+use crate::front_buffer::FrontBuffer;
+use crate::gui::Gui;
+use crate::hardware_emulation::HardwareEmulation;
+use crate::local_input::LocalInput;
+use crate::network_impairment::{ImpairmentConfig, NetworkImpairment};
+use crate::packet_log::{LogFormat, PACKET_LOG_TARGET};
+use crate::packet_pipeline::PacketPipeline;
+use crate::screensaver::Screensaver;
+use crate::sender_displays::{ClientDisplays, SenderDisplays};
+use crate::source_filter::{RateLimit, SourceFilter};
 use crate::udp_server::UdpServer;
-use crate::{command_executor::CommandExecutionContext, gui::Gui};
-use clap::Parser;
-use cli::Cli;
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use cli::{Cli, ClientMode, Command};
 use log::{info, LevelFilter};
-use servicepoint::*;
-use std::sync::{mpsc, RwLock};
+use servicepoint_simulator::{font_renderer::FontRenderer8x8, Simulator};
+use std::{process::ExitCode, sync::Arc, time::Duration};
 use winit::event_loop::{ControlFlow, EventLoop};
 
+mod check;
 mod cli;
-mod command_executor;
-mod cp437_font;
-mod font_renderer;
+mod command_kind;
+mod compositor;
+mod conformance;
+mod defects;
+mod diagnostics;
+mod firmware;
+mod front_buffer;
 mod gui;
 mod gui_window;
+mod hardware_emulation;
+mod local_input;
+mod metrics;
+mod network_impairment;
+mod packet_log;
+mod packet_pipeline;
+mod png;
+mod screensaver;
+mod sender_displays;
+mod source_filter;
 mod udp_server;
+mod web_viewer;
+mod websocket;
 
-fn main() {
+fn main() -> ExitCode {
     let mut cli = Cli::parse();
     if !(cli.gui.red || cli.gui.blue || cli.gui.green) {
         cli.gui.green = true;
     }
+    if cli.web.is_some() && cli.clients == ClientMode::Isolated {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--web shows the shared display, which stays empty with --clients isolated",
+            )
+            .exit();
+    }
 
-    init_logging(cli.verbose);
+    init_logging(cli.verbose, cli.log_format, cli.diagnostics);
     info!("starting with args: {:?}", &cli);
 
+    let font_renderer = cli
+        .font
+        .take()
+        .map(FontRenderer8x8::from_name)
+        .unwrap_or_else(FontRenderer8x8::default);
+    let simulator = Simulator::new(font_renderer).with_legacy(cli.legacy);
+    match cli.command.take() {
+        Some(Command::Check(options)) => {
+            let firmware = Firmware {
+                strict: cli.strict,
+                frame_marker: cli.frame_marker,
+                ..Firmware::default()
+            };
+            check::run(options, firmware, simulator)
+        }
+        None => run_gui(cli, simulator),
+    }
+}
+
+fn run_gui(cli: Cli, simulator: Simulator) -> ExitCode {
+    let inputs = udp_server::bind_sockets(&cli.bind).and_then(|sockets| {
+        udp_server::join_multicast(&sockets, &cli.multicast)?;
+        Ok((sockets, LocalInput::open(&cli.local_input)?))
+    });
+    let (sockets, local_input) = match inputs {
+        Ok(inputs) => inputs,
+        Err(err) => cli::exit_with_error(err),
+    };
+    if let Some(bind) = &cli.metrics {
+        if let Err(err) = metrics::serve(bind) {
+            cli::exit_with_error(format!(
+                "could not serve metrics on {bind}: {err}"
+            ));
+        }
+    }
+
     let event_loop = EventLoop::with_user_event()
         .build()
         .expect("could not create event loop");
     event_loop.set_control_flow(ControlFlow::Wait);
 
-    let display = RwLock::new(Bitmap::max_sized());
-    let luma = RwLock::new(BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT));
-    let (stop_udp_tx, stop_udp_rx) = mpsc::channel();
-    let font_renderer = cli
-        .font
-        .map(FontRenderer8x8::from_name)
-        .unwrap_or_else(FontRenderer8x8::default);
-    let context = CommandExecutionContext::new(&display, &luma, font_renderer);
-    let mut udp_server = UdpServer::new(
-        cli.bind,
-        stop_udp_rx,
-        context,
-        event_loop.create_proxy(),
+    if let Some(initial_state) = cli.initial_state {
+        simulator.restore(initial_state);
+    }
+    let defects = cli.defects.map(Arc::new);
+    let front =
+        Arc::new(FrontBuffer::new(simulator.snapshot(), defects.clone()));
+    let senders = SenderDisplays::new(cli.max_senders, defects);
+    let gui_senders = (cli.clients != ClientMode::Shared).then_some(&senders);
+    let clients = match cli.clients {
+        ClientMode::Shared => ClientDisplays::Shared,
+        ClientMode::Isolated => ClientDisplays::Isolated(&senders),
+        ClientMode::Composited => ClientDisplays::Composited(Compositor::new(
+            &senders, &simulator, cli.layers, cli.blend,
+        )),
+    };
+    let rate_limit = cli
+        .rate_limit
+        .map(|per_second| RateLimit::new(per_second, cli.rate_burst));
+    let source_filter = SourceFilter::new(cli.allow, cli.deny, rate_limit);
+    let impairment = ImpairmentConfig {
+        loss: cli.network.loss / 100.0,
+        duplicate: cli.network.duplicate / 100.0,
+        reorder_window: cli.network.reorder,
+        delay: Duration::from_millis(cli.network.delay),
+        jitter: Duration::from_millis(cli.network.jitter),
+        seed: cli.network.seed.unwrap_or_else(|| fastrand::u64(..)),
+    };
+    let network_impairment = impairment
+        .is_active()
+        .then(|| NetworkImpairment::new(impairment));
+    let hardware_emulation = cli.hardware.emulate_hardware.then(|| {
+        HardwareEmulation::new(
+            cli.hardware.command_costs,
+            Duration::from_millis(cli.hardware.latency),
+            cli.hardware.queue_size,
+        )
+    });
+    let udp_server = UdpServer::new(
+        sockets,
+        &simulator,
+        &front,
+        clients,
+        PacketPipeline::new(
+            source_filter,
+            network_impairment,
+            hardware_emulation,
+        ),
+        Box::new(event_loop.create_proxy()),
+        Firmware {
+            hard_reset: cli.hard_reset,
+            boot_splash: cli.boot_splash,
+            screensaver: cli.screensaver.idle_timeout.map(|idle_timeout| {
+                Screensaver::new(
+                    cli.screensaver.screensaver,
+                    cli.screensaver.screensaver_text,
+                    cli.screensaver.clock_utc_offset,
+                    idle_timeout,
+                )
+            }),
+            strict: cli.strict,
+            frame_marker: cli.frame_marker,
+        },
+    );
+    let udp_server = udp_server.and_then(|mut udp_server| {
+        udp_server.add_local_input(local_input)?;
+        Ok(udp_server)
+    });
+    let mut udp_server = match udp_server {
+        Ok(udp_server) => udp_server,
+        Err(err) => cli::exit_with_error(err),
+    };
+    if let Some(bind) = &cli.web {
+        let input = cli.web_input.then(|| udp_server.packet_queue());
+        if let Err(err) = web_viewer::serve(
+            bind,
+            Arc::clone(&front),
+            input,
+            cli.web_origin.clone(),
+        ) {
+            cli::exit_with_error(format!(
+                "could not serve web viewer on {bind}: {err}"
+            ));
+        }
+    }
+    let mut gui = Gui::new(
+        &simulator,
+        &front,
+        gui_senders,
+        cli.clients,
+        udp_server.stop_handle(),
+        cli.gui,
     );
-    let mut gui = Gui::new(&display, &luma, stop_udp_tx, cli.gui);
 
     std::thread::scope(move |scope| {
         scope.spawn(move || udp_server.run());
@@ -54,16 +203,27 @@ fn main() {
             .run_app(&mut gui)
             .expect("could not run event loop");
     });
+    ExitCode::SUCCESS
 }
 
-fn init_logging(debug: bool) {
+fn init_logging(debug: bool, format: LogFormat, diagnostics: bool) {
     let filter = if debug {
         LevelFilter::Debug
     } else {
         LevelFilter::Info
     };
-    env_logger::builder()
-        .filter_level(filter)
-        .parse_default_env()
-        .init();
+    let mut builder = env_logger::builder();
+    builder.filter_level(filter);
+    match format {
+        LogFormat::Text => {
+            builder.filter_module(PACKET_LOG_TARGET, LevelFilter::Off);
+        }
+        LogFormat::Json => {
+            builder.format(packet_log::format_json);
+        }
+    }
+    if !diagnostics {
+        builder.filter_module(DIAGNOSTICS_LOG_TARGET, LevelFilter::Off);
+    }
+    builder.parse_default_env().init();
 }
@@ -0,0 +1,629 @@
+use crate::command_kind::CommandKind;
+use log::{info, warn};
+use servicepoint::{CompressionCode, TryFromPacketError};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{Display, Write as _},
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Counters of everything the UDP server does, exported in the Prometheus text format.
+///
+/// Only the compression statistics are recorded unless the metrics are served,
+/// because they are also reported in the log.
+pub static METRICS: Metrics = Metrics::new();
+
+/// Sources beyond this are counted together, so a scan cannot grow the metrics forever.
+const MAX_TRACKED_SOURCES: usize = 4096;
+const EXECUTION_BUCKETS: [f64; 9] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.1,
+];
+/// How long answering a request may take, however slowly the client sends and receives.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Scrapers send far smaller requests, bigger ones are not read to the end.
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+#[derive(Debug)]
+pub struct Metrics {
+    /// whether the metrics that are only served over HTTP are recorded, see [serve]
+    enabled: AtomicBool,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    received: u64,
+    bytes: u64,
+    truncated: u64,
+    other_sources: u64,
+    sources: BTreeMap<IpAddr, u64>,
+    decoded: BTreeMap<CommandKind, u64>,
+    failed: BTreeMap<&'static str, u64>,
+    execution: BTreeMap<CommandKind, Histogram>,
+    /// by source and codec name, `None` are the sources beyond [MAX_TRACKED_SOURCES]
+    compression: BTreeMap<(Option<IpAddr>, &'static str), CompressionStats>,
+    compression_sources: BTreeSet<IpAddr>,
+}
+
+/// What one source sent with one codec.
+#[derive(Debug, Default)]
+struct CompressionStats {
+    packets: u64,
+    failed: u64,
+    compressed_bytes: u64,
+    decompressed_bytes: u64,
+    seconds: f64,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; EXECUTION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            inner: Mutex::new(Inner {
+                received: 0,
+                bytes: 0,
+                truncated: 0,
+                other_sources: 0,
+                sources: BTreeMap::new(),
+                decoded: BTreeMap::new(),
+                failed: BTreeMap::new(),
+                execution: BTreeMap::new(),
+                compression: BTreeMap::new(),
+                compression_sources: BTreeSet::new(),
+            }),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self, source: IpAddr, bytes: usize, truncated: bool) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.received += 1;
+        inner.bytes += bytes as u64;
+        inner.truncated += truncated as u64;
+        if inner.sources.len() < MAX_TRACKED_SOURCES
+            || inner.sources.contains_key(&source)
+        {
+            *inner.sources.entry(source).or_default() += 1;
+        } else {
+            inner.other_sources += 1;
+        }
+    }
+
+    pub fn decoded(&self, kind: CommandKind) {
+        if !self.is_enabled() {
+            return;
+        }
+        *self.inner.lock().unwrap().decoded.entry(kind).or_default() += 1;
+    }
+
+    pub fn too_short(&self) {
+        self.failed("too_short");
+    }
+
+    pub fn decode_failed(&self, error: &TryFromPacketError) {
+        self.failed(match error {
+            TryFromPacketError::InvalidCommand(_) => "invalid_command",
+            TryFromPacketError::UnexpectedPayloadSize { .. } => {
+                "unexpected_payload_size"
+            }
+            TryFromPacketError::ExtraneousHeaderValues => {
+                "extraneous_header_values"
+            }
+            TryFromPacketError::InvalidCompression(_) => "invalid_compression",
+            TryFromPacketError::DecompressionFailed => "decompression_failed",
+            TryFromPacketError::InvalidBrightness(_) => "invalid_brightness",
+            TryFromPacketError::InvalidUtf8(_) => "invalid_utf8",
+            TryFromPacketError::LoadBitmapFailed(_) => "load_bitmap_failed",
+        });
+    }
+
+    pub fn nonconforming(&self) {
+        self.failed("nonconforming");
+    }
+
+    /// Records a packet with a compressible command that was decoded.
+    pub fn decompressed(
+        &self,
+        source: IpAddr,
+        codec: CompressionCode,
+        compressed: usize,
+        decompressed: usize,
+        duration: Duration,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        let stats = inner.compression_stats(source, codec);
+        stats.packets += 1;
+        stats.compressed_bytes += compressed as u64;
+        stats.decompressed_bytes += decompressed as u64;
+        stats.seconds += duration.as_secs_f64();
+    }
+
+    pub fn decompression_failed(&self, source: IpAddr, codec: CompressionCode) {
+        let mut inner = self.inner.lock().unwrap();
+        let stats = inner.compression_stats(source, codec);
+        stats.packets += 1;
+        stats.failed += 1;
+    }
+
+    pub fn execution_failed(&self) {
+        self.failed("execution_failed");
+    }
+
+    pub fn executed(&self, kind: CommandKind, duration: Duration) {
+        if !self.is_enabled() {
+            return;
+        }
+        let seconds = duration.as_secs_f64();
+        let mut inner = self.inner.lock().unwrap();
+        let histogram = inner.execution.entry(kind).or_default();
+        for (bucket, bound) in
+            histogram.buckets.iter_mut().zip(EXECUTION_BUCKETS)
+        {
+            *bucket += (seconds <= bound) as u64;
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    fn failed(&self, error: &'static str) {
+        if !self.is_enabled() {
+            return;
+        }
+        *self.inner.lock().unwrap().failed.entry(error).or_default() += 1;
+    }
+
+    /// Formats the compression statistics as a table for the log.
+    pub fn compression_report(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        if inner.compression.is_empty() {
+            return String::from(
+                "no packets with a compressible command received yet",
+            );
+        }
+
+        let mut out = format!(
+            "compression by source and codec:\n{:<40} {:<12} {:>8} {:>7} {:>10} {:>12} {:>6} {:>11}",
+            "source",
+            "codec",
+            "packets",
+            "failed",
+            "received",
+            "decompressed",
+            "ratio",
+            "avg decode"
+        );
+        for ((source, codec), stats) in &inner.compression {
+            let source = source
+                .map_or(String::from("other"), |source| source.to_string());
+            let decoded = stats.packets - stats.failed;
+            let ratio = match stats.decompressed_bytes {
+                0 => String::from("-"),
+                decompressed => format!(
+                    "{:.1}%",
+                    stats.compressed_bytes as f64 / decompressed as f64 * 100.0
+                ),
+            };
+            let decode = match decoded {
+                0 => String::from("-"),
+                decoded => {
+                    format!("{:.3} ms", stats.seconds * 1000.0 / decoded as f64)
+                }
+            };
+            let _ = write!(
+                out,
+                "\n{source:<40} {codec:<12} {:>8} {:>7} {:>10} {:>12} {ratio:>6} {decode:>11}",
+                stats.packets,
+                stats.failed,
+                stats.compressed_bytes,
+                stats.decompressed_bytes,
+            );
+        }
+        out
+    }
+
+    /// Formats all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        let received = [(String::new(), inner.received)];
+        counter(
+            &mut out,
+            "packets_received_total",
+            "packets received via UDP, stdin, the Unix socket or the web viewer, before filtering",
+            received,
+        );
+        let bytes = [(String::new(), inner.bytes)];
+        counter(
+            &mut out,
+            "bytes_received_total",
+            "bytes received in packets",
+            bytes,
+        );
+        let truncated = [(String::new(), inner.truncated)];
+        counter(
+            &mut out,
+            "packets_truncated_total",
+            "packets that filled the receive buffer and may be truncated",
+            truncated,
+        );
+
+        let sources = inner
+            .sources
+            .iter()
+            .map(|(source, count)| (format!("source=\"{source}\""), *count))
+            .chain((inner.other_sources > 0).then(|| {
+                (String::from("source=\"other\""), inner.other_sources)
+            }));
+        counter(
+            &mut out,
+            "source_packets_total",
+            "packets received per source address",
+            sources,
+        );
+        let decoded = inner
+            .decoded
+            .iter()
+            .map(|(kind, count)| (format!("command=\"{kind}\""), *count));
+        counter(
+            &mut out,
+            "packets_decoded_total",
+            "packets decoded into a command",
+            decoded,
+        );
+        let failed = inner
+            .failed
+            .iter()
+            .map(|(error, count)| (format!("error=\"{error}\""), *count));
+        counter(
+            &mut out,
+            "packets_failed_total",
+            "packets that could not be decoded or executed",
+            failed,
+        );
+
+        let labels = |(source, codec): &(Option<IpAddr>, &str)| {
+            let source = source
+                .map_or(String::from("other"), |source| source.to_string());
+            format!("source=\"{source}\",codec=\"{codec}\"")
+        };
+        let compression = &inner.compression;
+        counter(
+            &mut out,
+            "compressed_packets_total",
+            "packets with a compressible command per source and codec",
+            compression
+                .iter()
+                .map(|(key, stats)| (labels(key), stats.packets)),
+        );
+        counter(
+            &mut out,
+            "decompression_failures_total",
+            "packets that could not be decompressed per source and codec",
+            compression
+                .iter()
+                .map(|(key, stats)| (labels(key), stats.failed)),
+        );
+        counter(
+            &mut out,
+            "compressed_bytes_total",
+            "payload bytes of decoded packets as received",
+            compression
+                .iter()
+                .map(|(key, stats)| (labels(key), stats.compressed_bytes)),
+        );
+        counter(
+            &mut out,
+            "decompressed_bytes_total",
+            "payload bytes of decoded packets after decompression",
+            compression
+                .iter()
+                .map(|(key, stats)| (labels(key), stats.decompressed_bytes)),
+        );
+        counter(
+            &mut out,
+            "decompression_seconds_total",
+            "time spent decoding packets with a compressible command",
+            compression
+                .iter()
+                .map(|(key, stats)| (labels(key), stats.seconds)),
+        );
+
+        let name = "servicepoint_command_execution_seconds";
+        let _ = writeln!(out, "# HELP {name} time spent executing commands");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (kind, histogram) in &inner.execution {
+            let command = format!("command=\"{kind}\"");
+            for (bucket, bound) in
+                histogram.buckets.iter().zip(EXECUTION_BUCKETS)
+            {
+                let _ = writeln!(
+                    out,
+                    "{name}_bucket{{{command},le=\"{bound}\"}} {bucket}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{command},le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(out, "{name}_sum{{{command}}} {}", histogram.sum);
+            let _ =
+                writeln!(out, "{name}_count{{{command}}} {}", histogram.count);
+        }
+        out
+    }
+}
+
+impl Inner {
+    fn compression_stats(
+        &mut self,
+        source: IpAddr,
+        codec: CompressionCode,
+    ) -> &mut CompressionStats {
+        let is_tracked = self.compression_sources.contains(&source)
+            || (self.compression_sources.len() < MAX_TRACKED_SOURCES
+                && self.compression_sources.insert(source));
+        let source = is_tracked.then_some(source);
+        self.compression
+            .entry((source, codec_name(codec)))
+            .or_default()
+    }
+}
+
+fn codec_name(codec: CompressionCode) -> &'static str {
+    match codec {
+        CompressionCode::Uncompressed => "uncompressed",
+        CompressionCode::Zlib => "zlib",
+        CompressionCode::Bzip2 => "bzip2",
+        CompressionCode::Lzma => "lzma",
+        CompressionCode::Zstd => "zstd",
+    }
+}
+
+fn counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, impl Display)>,
+) {
+    let _ = writeln!(out, "# HELP servicepoint_{name} {help}");
+    let _ = writeln!(out, "# TYPE servicepoint_{name} counter");
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "servicepoint_{name} {value}");
+        } else {
+            let _ = writeln!(out, "servicepoint_{name}{{{labels}}} {value}");
+        }
+    }
+}
+
+/// Serves the metrics over HTTP on a background thread.
+pub fn serve(bind: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(bind)?;
+    info!(
+        "serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    METRICS.enabled.store(true, Ordering::Relaxed);
+    std::thread::Builder::new()
+        .name(String::from("metrics"))
+        .spawn(move || {
+            // one request at a time, which is limited in size and time so no client can block the others for long
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| {
+                    respond(WithDeadline::new(stream, REQUEST_TIMEOUT))
+                });
+                if let Err(err) = result {
+                    warn!("could not answer metrics request: {err}");
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn respond(mut stream: impl Read + Write) -> io::Result<()> {
+    let path = read_request_path(&mut stream)?;
+    let (status, body) = match path.as_deref() {
+        Some("/metrics") => ("200 OK", METRICS.render()),
+        Some(_) => ("404 Not Found", String::from("not found, try /metrics\n")),
+        None => (
+            "400 Bad Request",
+            format!("request is larger than {MAX_REQUEST_SIZE} bytes\n"),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// Reads the request line and the headers, returning the path of the request.
+///
+/// Returns `None` if the headers do not end within [MAX_REQUEST_SIZE] bytes.
+fn read_request_path(stream: impl Read) -> io::Result<Option<String>> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are not needed, but have to be read before answering
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            if reader.get_ref().limit() == 0 {
+                return Ok(None);
+            }
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        if line.trim_end().is_empty() {
+            break;
+        }
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    Ok(Some(path.to_owned()))
+}
+
+/// A connection that fails with [ErrorKind::TimedOut] once the deadline passed,
+/// even if the client keeps sending or receiving a few bytes at a time.
+struct WithDeadline {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl WithDeadline {
+    fn new(stream: TcpStream, timeout: Duration) -> Self {
+        Self {
+            stream,
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    fn remaining(&self) -> io::Result<Duration> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        Ok(remaining)
+    }
+}
+
+impl Read for WithDeadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for WithDeadline {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.remaining()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const SOURCE: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn record_packet(metrics: &Metrics) {
+        metrics.received(SOURCE, 100, false);
+        metrics.decoded(CommandKind::Clear);
+        metrics.executed(CommandKind::Clear, Duration::from_micros(50));
+        metrics.too_short();
+        metrics.decompressed(
+            SOURCE,
+            CompressionCode::Zlib,
+            10,
+            100,
+            Duration::from_micros(20),
+        );
+    }
+
+    #[test]
+    fn records_only_compression_unless_enabled() {
+        let metrics = Metrics::new();
+        record_packet(&metrics);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("servicepoint_packets_received_total 0\n"));
+        assert!(!rendered.contains("command=\"clear\""), "{rendered}");
+        assert!(!rendered.contains("error=\"too_short\""), "{rendered}");
+        assert!(rendered.contains(
+            "servicepoint_compressed_packets_total{source=\"127.0.0.1\",codec=\"zlib\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn records_everything_when_enabled() {
+        let metrics = Metrics::new();
+        metrics.enabled.store(true, Ordering::Relaxed);
+        record_packet(&metrics);
+
+        let rendered = metrics.render();
+        for expected in [
+            "servicepoint_packets_received_total 1\n",
+            "servicepoint_bytes_received_total 100\n",
+            "servicepoint_source_packets_total{source=\"127.0.0.1\"} 1\n",
+            "servicepoint_packets_decoded_total{command=\"clear\"} 1\n",
+            "servicepoint_packets_failed_total{error=\"too_short\"} 1\n",
+            "servicepoint_command_execution_seconds_count{command=\"clear\"} 1\n",
+            "servicepoint_compressed_packets_total{source=\"127.0.0.1\",codec=\"zlib\"} 1\n",
+        ] {
+            assert!(rendered.contains(expected), "{expected}in {rendered}");
+        }
+    }
+
+    #[test]
+    fn read_request_path_skips_headers() {
+        let request = "GET /metrics HTTP/1.1\r\nHost: a\r\nAccept: */*\r\n\r\n";
+        assert_eq!(
+            read_request_path(request.as_bytes()).unwrap().as_deref(),
+            Some("/metrics")
+        );
+    }
+
+    #[test]
+    fn read_request_path_limits_size() {
+        assert_eq!(read_request_path(io::repeat(b'a')).unwrap(), None);
+
+        let truncated = "GET /metrics HTTP/1.1\r\nHost: a\r\n";
+        let err = read_request_path(truncated.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn slow_client_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let timeout = Duration::from_millis(200);
+        let start = Instant::now();
+
+        // a byte every 50ms would keep a timeout per read from ever elapsing
+        let sender = std::thread::spawn(move || {
+            while client.write_all(b"G").is_ok() {
+                std::thread::sleep(Duration::from_millis(50));
+                if start.elapsed() > Duration::from_secs(5) {
+                    break;
+                }
+            }
+        });
+        let err = respond(WithDeadline::new(stream, timeout)).unwrap_err();
+
+        assert!(
+            matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock),
+            "{err}"
+        );
+        assert!(start.elapsed() < Duration::from_secs(2));
+        sender.join().unwrap();
+    }
+}
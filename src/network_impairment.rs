@@ -0,0 +1,230 @@
+use log::info;
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+const REORDER_TIMEOUT: Duration = Duration::from_millis(100);
+const DROP_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Settings for [NetworkImpairment]. Probabilities are between 0 and 1.
+#[derive(Debug, Clone, Copy)]
+pub struct ImpairmentConfig {
+    pub loss: f64,
+    pub duplicate: f64,
+    pub reorder_window: usize,
+    pub delay: Duration,
+    pub jitter: Duration,
+    pub seed: u64,
+}
+
+/// Makes the simulator behave like it is behind an unreliable network.
+///
+/// Received packets are randomly dropped and duplicated, held back to be
+/// reordered and finally delayed. All random decisions come from one seeded
+/// generator, so a run can be reproduced if the packets arrive the same way.
+#[derive(Debug)]
+pub struct NetworkImpairment {
+    config: ImpairmentConfig,
+    rng: fastrand::Rng,
+    reorder_buffer: Vec<(Instant, Vec<u8>, SocketAddr)>,
+    delayed: Vec<(Instant, Vec<u8>, SocketAddr)>,
+    dropped: usize,
+    last_drop_log: Instant,
+}
+
+impl ImpairmentConfig {
+    pub fn is_active(&self) -> bool {
+        self.loss > 0.0
+            || self.duplicate > 0.0
+            || self.reorder_window > 0
+            || !self.delay.is_zero()
+            || !self.jitter.is_zero()
+    }
+}
+
+impl NetworkImpairment {
+    pub fn new(config: ImpairmentConfig) -> Self {
+        info!("impairing network with {config:?}");
+        Self {
+            rng: fastrand::Rng::with_seed(config.seed),
+            config,
+            reorder_buffer: Vec::with_capacity(config.reorder_window + 1),
+            delayed: Vec::new(),
+            dropped: 0,
+            last_drop_log: Instant::now(),
+        }
+    }
+
+    /// Sends a received packet through the network, returns `false` if it was lost.
+    pub fn push(&mut self, packet: Vec<u8>, source: SocketAddr) -> bool {
+        if self.rng.f64() < self.config.loss {
+            self.dropped += 1;
+            self.log_drops();
+            return false;
+        }
+        if self.rng.f64() < self.config.duplicate {
+            self.hold_for_reorder(packet.clone(), source);
+        }
+        self.hold_for_reorder(packet, source);
+        true
+    }
+
+    /// Returns the next packet that made it through the network, if any.
+    pub fn pop(&mut self) -> Option<(Vec<u8>, SocketAddr)> {
+        // do not hold packets back forever if no more packets arrive
+        while self
+            .reorder_buffer
+            .first()
+            .is_some_and(|(held, _, _)| held.elapsed() >= REORDER_TIMEOUT)
+        {
+            let (_, packet, source) = self.reorder_buffer.remove(0);
+            self.delay(packet, source);
+        }
+
+        let (release, _, _) = self.delayed.first()?;
+        if *release > Instant::now() {
+            return None;
+        }
+        let (_, packet, source) = self.delayed.remove(0);
+        Some((packet, source))
+    }
+
+    /// When the next held back packet is released, if there is one.
+    pub fn next_release(&self) -> Option<Instant> {
+        let reorder_timeout = self
+            .reorder_buffer
+            .first()
+            .map(|(held, _, _)| *held + REORDER_TIMEOUT);
+        let delayed = self.delayed.first().map(|(release, _, _)| *release);
+        reorder_timeout.into_iter().chain(delayed).min()
+    }
+
+    fn hold_for_reorder(&mut self, packet: Vec<u8>, source: SocketAddr) {
+        self.reorder_buffer.push((Instant::now(), packet, source));
+        if self.reorder_buffer.len() > self.config.reorder_window {
+            let index = self.rng.usize(..self.reorder_buffer.len());
+            let (_, packet, source) = self.reorder_buffer.remove(index);
+            self.delay(packet, source);
+        }
+    }
+
+    fn delay(&mut self, packet: Vec<u8>, source: SocketAddr) {
+        let jitter = self.config.jitter.mul_f64(self.rng.f64());
+        let release = Instant::now() + self.config.delay + jitter;
+        let index = self.delayed.partition_point(|(r, _, _)| *r <= release);
+        self.delayed.insert(index, (release, packet, source));
+    }
+
+    fn log_drops(&mut self) {
+        if self.last_drop_log.elapsed() < DROP_LOG_INTERVAL {
+            return;
+        }
+        info!(
+            "network impairment dropped {} packets in the last {}s",
+            self.dropped,
+            self.last_drop_log.elapsed().as_secs()
+        );
+        self.dropped = 0;
+        self.last_drop_log = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    const SOURCE: SocketAddr =
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2342));
+
+    fn config(seed: u64) -> ImpairmentConfig {
+        ImpairmentConfig {
+            loss: 0.2,
+            duplicate: 0.2,
+            reorder_window: 4,
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            seed,
+        }
+    }
+
+    /// Pushes numbered packets, returning which were accepted and the numbers in the order they came out.
+    fn run(config: ImpairmentConfig) -> (Vec<bool>, Vec<u8>) {
+        let mut network = NetworkImpairment::new(config);
+        let mut accepted = Vec::new();
+        let mut received = Vec::new();
+        for number in 0..200 {
+            accepted.push(network.push(vec![number], SOURCE));
+            while let Some((packet, source)) = network.pop() {
+                assert_eq!(source, SOURCE);
+                received.push(packet[0]);
+            }
+        }
+        (accepted, received)
+    }
+
+    #[test]
+    fn same_seed_same_impairment() {
+        let (accepted, received) = run(config(42));
+
+        assert_eq!(run(config(42)), (accepted.clone(), received.clone()));
+
+        // every kind of impairment happened
+        assert!(accepted.contains(&false));
+        assert!((0..200).any(|number| received
+            .iter()
+            .filter(|&&n| n == number)
+            .count()
+            > 1));
+        assert!(received.windows(2).any(|pair| pair[0] > pair[1]));
+        // lost packets never arrive
+        for (number, accepted) in accepted.iter().enumerate() {
+            if !accepted {
+                assert!(!received.contains(&(number as u8)), "{number}");
+            }
+        }
+    }
+
+    #[test]
+    fn different_seed_different_impairment() {
+        let (accepted, received) = run(config(42));
+        let (other_accepted, other_received) = run(config(43));
+
+        assert_ne!(accepted, other_accepted);
+        assert_ne!(received, other_received);
+    }
+
+    #[test]
+    fn unimpaired_packets_pass_in_order() {
+        let config = ImpairmentConfig {
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder_window: 0,
+            ..config(42)
+        };
+        assert!(!config.is_active());
+
+        let (accepted, received) = run(config);
+
+        assert!(accepted.iter().all(|&accepted| accepted));
+        assert_eq!(received, (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reorder_timeout_releases_held_packets() {
+        let mut network = NetworkImpairment::new(ImpairmentConfig {
+            loss: 0.0,
+            duplicate: 0.0,
+            ..config(42)
+        });
+        assert!(network.push(vec![1], SOURCE));
+        assert_eq!(network.pop(), None);
+
+        let release = network.next_release().unwrap();
+        std::thread::sleep(release.saturating_duration_since(Instant::now()));
+
+        assert_eq!(network.pop(), Some((vec![1], SOURCE)));
+        assert_eq!(network.next_release(), None);
+    }
+}
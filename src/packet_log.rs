@@ -0,0 +1,199 @@
+use crate::{command_kind::CommandKind, packet_pipeline::DropReason};
+use clap::ValueEnum;
+use env_logger::fmt::Formatter;
+use log::{info, log_enabled, Level, Record};
+use servicepoint::{Header, Packet};
+use servicepoint_simulator::command_executor::ExecutionResult;
+use std::{fmt::Write as _, io::Write, net::SocketAddr};
+
+/// Log target of the [PacketEvent]s, only enabled with [LogFormat::Json].
+pub const PACKET_LOG_TARGET: &str = "packets";
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// human-readable lines
+    #[default]
+    Text,
+    /// one JSON object per line, including an event for every packet
+    Json,
+}
+
+/// What happened to one received packet, logged as a single structured event.
+#[derive(Debug)]
+pub struct PacketEvent {
+    source: SocketAddr,
+    length: usize,
+    header: Option<Header>,
+    command: Option<CommandKind>,
+    result: &'static str,
+    error: Option<String>,
+    reason: Option<DropReason>,
+}
+
+impl PacketEvent {
+    pub fn new(source: SocketAddr, packet: &[u8]) -> Self {
+        // only the header, so the payload is not copied
+        let header = packet
+            .get(..size_of::<Header>())
+            .and_then(|header| Packet::try_from(header).ok())
+            .map(|packet| packet.header);
+        Self {
+            source,
+            length: packet.len(),
+            header,
+            command: None,
+            result: "invalid",
+            error: None,
+            reason: None,
+        }
+    }
+
+    pub fn decoded(&mut self, command: CommandKind) {
+        self.command = Some(command);
+    }
+
+    pub fn failed(&mut self, error: String) {
+        self.error = Some(error);
+    }
+
+    /// The packet was dropped before it could be decoded.
+    pub fn dropped(&mut self, reason: DropReason) {
+        self.result = "dropped";
+        self.reason = Some(reason);
+    }
+
+    pub fn executed(&mut self, result: ExecutionResult) {
+        self.result = match result {
+            ExecutionResult::Success => "success",
+            ExecutionResult::Failure => "failure",
+            ExecutionResult::Shutdown => "shutdown",
+        };
+    }
+
+    pub fn log(&self) {
+        if !log_enabled!(target: PACKET_LOG_TARGET, Level::Info) {
+            return;
+        }
+        info!(target: PACKET_LOG_TARGET, "{}", self.fields());
+    }
+
+    /// The fields of the event as JSON, without the surrounding braces.
+    fn fields(&self) -> String {
+        let mut fields = format!(
+            "\"event\":\"packet\",\"source\":{},\"length\":{}",
+            json_string(&self.source.to_string()),
+            self.length
+        );
+        if let Some(Header {
+            command_code,
+            a,
+            b,
+            c,
+            d,
+        }) = self.header
+        {
+            let _ = write!(
+                fields,
+                ",\"header\":{{\"command_code\":{command_code},\"a\":{a},\"b\":{b},\"c\":{c},\"d\":{d}}}"
+            );
+        }
+        if let Some(command) = self.command {
+            let _ = write!(fields, ",\"command\":\"{command}\"");
+        }
+        let _ = write!(fields, ",\"result\":\"{}\"", self.result);
+        if let Some(reason) = self.reason {
+            let _ = write!(fields, ",\"reason\":\"{}\"", reason.name());
+        }
+        if let Some(error) = &self.error {
+            let _ = write!(fields, ",\"error\":{}", json_string(error));
+        }
+        fields
+    }
+}
+
+/// Formats a log record as a JSON object on one line.
+///
+/// Records of [PacketEvent]s already contain their fields as JSON and are
+/// written as they are, all others become a message.
+pub fn format_json(
+    buf: &mut Formatter,
+    record: &Record,
+) -> std::io::Result<()> {
+    write!(
+        buf,
+        "{{\"timestamp\":\"{}\",\"level\":\"{}\",",
+        buf.timestamp_millis(),
+        record.level()
+    )?;
+    if record.target() == PACKET_LOG_TARGET {
+        writeln!(buf, "{}}}", record.args())
+    } else {
+        writeln!(
+            buf,
+            "\"event\":\"log\",\"target\":{},\"message\":{}}}",
+            json_string(record.target()),
+            json_string(&record.args().to_string())
+        )
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for char in value.chars() {
+        match char {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            char if char.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", char as u32);
+            }
+            char => escaped.push(char),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const SOURCE: SocketAddr =
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 2342);
+
+    #[test]
+    fn executed_event() {
+        let mut event =
+            PacketEvent::new(SOURCE, &[0, 2, 0, 0, 0, 0, 0, 0, 0, 0]);
+        event.decoded(CommandKind::Clear);
+        event.executed(ExecutionResult::Success);
+        assert_eq!(
+            event.fields(),
+            r#""event":"packet","source":"10.0.0.1:2342","length":10,"header":{"command_code":2,"a":0,"b":0,"c":0,"d":0},"command":"clear","result":"success""#
+        );
+    }
+
+    #[test]
+    fn invalid_event() {
+        let mut event = PacketEvent::new(SOURCE, &[0, 2, 0]);
+        event.failed(String::from("too \"short\""));
+        assert_eq!(
+            event.fields(),
+            r#""event":"packet","source":"10.0.0.1:2342","length":3,"result":"invalid","error":"too \"short\"""#
+        );
+    }
+
+    #[test]
+    fn dropped_event() {
+        let mut event = PacketEvent::new(SOURCE, &[0, 2, 0]);
+        event.dropped(DropReason::RateLimited);
+        assert_eq!(
+            event.fields(),
+            r#""event":"packet","source":"10.0.0.1:2342","length":3,"result":"dropped","reason":"rate limited""#
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{
+    command_kind::CommandKind, hardware_emulation::HardwareEmulation,
+    network_impairment::NetworkImpairment, packet_log::PacketEvent,
+    source_filter::SourceFilter,
+};
+use log::debug;
+use std::{collections::VecDeque, net::SocketAddr, time::Instant};
+
+/// The stages a received datagram passes before it is decoded.
+///
+/// Packets are first checked by the [SourceFilter], then travel through the
+/// [NetworkImpairment] and wait for the [HardwareEmulation], if enabled.
+#[derive(Debug)]
+pub struct PacketPipeline {
+    source_filter: SourceFilter,
+    network_impairment: Option<NetworkImpairment>,
+    hardware_emulation: Option<HardwareEmulation>,
+    ready: VecDeque<(Vec<u8>, SocketAddr)>,
+}
+
+/// Why a packet never reached the decoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    Denied,
+    RateLimited,
+    Lost,
+    QueueFull,
+}
+
+impl PacketPipeline {
+    pub fn new(
+        source_filter: SourceFilter,
+        network_impairment: Option<NetworkImpairment>,
+        hardware_emulation: Option<HardwareEmulation>,
+    ) -> Self {
+        Self {
+            source_filter,
+            network_impairment,
+            hardware_emulation,
+            ready: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, packet: &[u8], source: SocketAddr) {
+        if let Err(reason) = self.source_filter.accept(source.ip()) {
+            debug!("dropping packet from {source}");
+            dropped(packet, source, reason);
+            return;
+        }
+
+        match &mut self.network_impairment {
+            Some(network) => {
+                if !network.push(packet.to_vec(), source) {
+                    dropped(packet, source, DropReason::Lost);
+                }
+            }
+            None => self.arrive(packet.to_vec(), source),
+        }
+    }
+
+    /// Returns the next packet to decode, if there is one.
+    pub fn pop(&mut self) -> Option<(Vec<u8>, SocketAddr)> {
+        while let Some((packet, source)) = self
+            .network_impairment
+            .as_mut()
+            .and_then(NetworkImpairment::pop)
+        {
+            self.arrive(packet, source);
+        }
+
+        match &mut self.hardware_emulation {
+            Some(emulation) => emulation.dequeue(),
+            None => self.ready.pop_front(),
+        }
+    }
+
+    /// When [Self::pop] returns the next packet at the earliest, if there is one.
+    pub fn next_ready(&self) -> Option<Instant> {
+        let released = self
+            .network_impairment
+            .as_ref()
+            .and_then(NetworkImpairment::next_release);
+        let ready = match &self.hardware_emulation {
+            Some(emulation) => emulation.next_ready(),
+            None => (!self.ready.is_empty()).then(Instant::now),
+        };
+        released.into_iter().chain(ready).min()
+    }
+
+    /// Has to be called for every command taken from the pipeline.
+    pub fn charge(&mut self, kind: CommandKind) {
+        if let Some(emulation) = &mut self.hardware_emulation {
+            emulation.charge(kind);
+        }
+    }
+
+    fn arrive(&mut self, packet: Vec<u8>, source: SocketAddr) {
+        match &mut self.hardware_emulation {
+            Some(emulation) => {
+                if let Err(packet) = emulation.enqueue(packet, source) {
+                    dropped(&packet, source, DropReason::QueueFull);
+                }
+            }
+            None => self.ready.push_back((packet, source)),
+        }
+    }
+}
+
+impl DropReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DropReason::Denied => "denied",
+            DropReason::RateLimited => "rate limited",
+            DropReason::Lost => "lost by network impairment",
+            DropReason::QueueFull => "hardware queue full",
+        }
+    }
+}
+
+fn dropped(packet: &[u8], source: SocketAddr, reason: DropReason) {
+    let mut event = PacketEvent::new(source, packet);
+    event.dropped(reason);
+    event.log();
+}
+
+impl Default for PacketPipeline {
+    fn default() -> Self {
+        Self::new(SourceFilter::new(Vec::new(), Vec::new(), None), None, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        time::Duration,
+    };
+
+    const SOURCE: SocketAddr =
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2342));
+
+    #[test]
+    fn packets_pass_in_order() {
+        let mut pipeline = PacketPipeline::default();
+        pipeline.push(&[1], SOURCE);
+        pipeline.push(&[2], SOURCE);
+
+        assert_eq!(pipeline.pop(), Some((vec![1], SOURCE)));
+        assert_eq!(pipeline.pop(), Some((vec![2], SOURCE)));
+        assert_eq!(pipeline.pop(), None);
+        assert_eq!(pipeline.next_ready(), None);
+    }
+
+    #[test]
+    fn full_hardware_queue_drops_packets() {
+        let hardware = HardwareEmulation::new(Vec::new(), Duration::ZERO, 2);
+        let mut pipeline = PacketPipeline::new(
+            SourceFilter::new(Vec::new(), Vec::new(), None),
+            None,
+            Some(hardware),
+        );
+        for number in 1..=3 {
+            pipeline.push(&[number], SOURCE);
+        }
+
+        assert_eq!(pipeline.pop(), Some((vec![1], SOURCE)));
+        pipeline.charge(CommandKind::HardReset);
+        assert_eq!(pipeline.pop(), Some((vec![2], SOURCE)));
+        pipeline.charge(CommandKind::HardReset);
+        assert_eq!(pipeline.pop(), None);
+    }
+}
@@ -0,0 +1,371 @@
+//! Reading and writing PNG images for comparing the display with a reference image.
+//!
+//! Images of every color type and bit depth, interlaced or not, are converted to 8 bit RGB.
+//! Transparent pixels are decoded as if drawn onto black.
+
+use ::png::{
+    BitDepth, ColorType, Decoder, DecodingError, Encoder, Transformations,
+};
+use std::io::Cursor;
+
+/// An 8 bit RGB image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl RgbImage {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![[0; 3]; width * height],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> [u8; 3] {
+        self.pixels[x + y * self.width]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: [u8; 3]) {
+        self.pixels[x + y * self.width] = color;
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut png = Vec::new();
+        let mut encoder =
+            Encoder::new(&mut png, self.width as u32, self.height as u32);
+        encoder.set_color(ColorType::Rgb);
+        encoder.set_depth(BitDepth::Eight);
+        let mut writer = encoder.write_header().expect("writing to a vec");
+        writer
+            .write_image_data(self.pixels.as_flattened())
+            .expect("writing to a vec");
+        writer.finish().expect("writing to a vec");
+        png
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DecodingError> {
+        let mut decoder = Decoder::new(Cursor::new(data));
+        // palette entries, samples with less or more than 8 bits and tRNS chunks become 8 bit channels
+        decoder.set_transformations(
+            Transformations::EXPAND | Transformations::STRIP_16,
+        );
+        let mut reader = decoder.read_info()?;
+        let mut buffer = vec![
+            0;
+            reader
+                .output_buffer_size()
+                .ok_or(DecodingError::LimitsExceeded)?
+        ];
+        let info = reader.next_frame(&mut buffer)?;
+
+        let (width, height) = (info.width as usize, info.height as usize);
+        let channels = info.color_type.samples();
+        let mut image = RgbImage::new(width, height);
+        for (y, line) in buffer.chunks_exact(info.line_size).enumerate() {
+            for (x, pixel) in line.chunks_exact(channels).enumerate() {
+                let (color, alpha) = match *pixel {
+                    [gray] => ([gray; 3], u8::MAX),
+                    [gray, alpha] => ([gray; 3], alpha),
+                    [r, g, b] => ([r, g, b], u8::MAX),
+                    [r, g, b, alpha] => ([r, g, b], alpha),
+                    _ => unreachable!("at most four channels"),
+                };
+                image.set(x, y, color.map(|value| onto_black(value, alpha)));
+            }
+        }
+        Ok(image)
+    }
+}
+
+fn onto_black(value: u8, alpha: u8) -> u8 {
+    ((value as u16 * alpha as u16 + u8::MAX as u16 / 2) / u8::MAX as u16) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::ZlibEncoder, Compression, Crc};
+    use std::io::Write;
+
+    /// The samples 0, 1/3, 2/3 and 1 of the maximum, which all bit depths can hold.
+    const THIRDS: [u8; 4] = [0, 85, 170, 255];
+
+    /// Packs samples with less than 8 bits into bytes, leftmost first.
+    fn pack(samples: &[u8], depth: u8) -> Vec<u8> {
+        let per_byte = 8 / depth as usize;
+        samples
+            .chunks(per_byte)
+            .map(|chunk| {
+                chunk.iter().enumerate().fold(0, |byte, (index, sample)| {
+                    byte | sample << (8 - depth as usize * (index + 1))
+                })
+            })
+            .collect()
+    }
+
+    /// Encodes a single row with the PNG encoder, which is independent of [RgbImage::decode].
+    fn encode_row(
+        width: u32,
+        color: ColorType,
+        depth: BitDepth,
+        data: &[u8],
+        configure: impl FnOnce(&mut Encoder<&mut Vec<u8>>),
+    ) -> Vec<u8> {
+        let mut png = Vec::new();
+        let mut encoder = Encoder::new(&mut png, width, 1);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        configure(&mut encoder);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(data).unwrap();
+        writer.finish().unwrap();
+        png
+    }
+
+    fn row(image: &RgbImage, y: usize) -> Vec<[u8; 3]> {
+        (0..image.width).map(|x| image.get(x, y)).collect()
+    }
+
+    fn gray(values: &[u8]) -> Vec<[u8; 3]> {
+        values.iter().map(|&value| [value; 3]).collect()
+    }
+
+    fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+        let mut crc = Crc::new();
+        crc.update(kind);
+        crc.update(body);
+        png.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        png.extend_from_slice(kind);
+        png.extend_from_slice(body);
+        png.extend_from_slice(&crc.sum().to_be_bytes());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut image = RgbImage::new(7, 3);
+        image.set(0, 0, [1, 2, 3]);
+        image.set(6, 2, [0xff, 0x80, 0]);
+        image.set(3, 1, [0x60; 3]);
+
+        let decoded = RgbImage::decode(&image.encode()).unwrap();
+
+        assert_eq!(decoded, image);
+    }
+
+    #[test]
+    fn decode_grayscale_of_every_bit_depth() {
+        let low_depths =
+            [(BitDepth::One, 1), (BitDepth::Two, 2), (BitDepth::Four, 4)];
+        for (depth, bits) in low_depths {
+            let max = (1u8 << bits) - 1;
+            let samples = [0, max / 3, max / 3 * 2, max];
+            let png = encode_row(
+                4,
+                ColorType::Grayscale,
+                depth,
+                &pack(&samples, bits),
+                |_| {},
+            );
+            let image = RgbImage::decode(&png).unwrap();
+            let expected = if bits == 1 {
+                gray(&[0, 0, 0, 255])
+            } else {
+                gray(&THIRDS)
+            };
+            assert_eq!(row(&image, 0), expected, "{bits} bits");
+        }
+
+        let png = encode_row(
+            4,
+            ColorType::Grayscale,
+            BitDepth::Eight,
+            &THIRDS,
+            |_| {},
+        );
+        assert_eq!(row(&RgbImage::decode(&png).unwrap(), 0), gray(&THIRDS));
+
+        let wide = THIRDS.map(|value| u16::from(value) * 0x101);
+        let png = encode_row(
+            4,
+            ColorType::Grayscale,
+            BitDepth::Sixteen,
+            &wide.map(u16::to_be_bytes).concat(),
+            |_| {},
+        );
+        assert_eq!(row(&RgbImage::decode(&png).unwrap(), 0), gray(&THIRDS));
+    }
+
+    #[test]
+    fn decode_palette_of_every_bit_depth() {
+        let palette = [[0xff, 0, 0], [0, 0xff, 0], [0, 0, 0xff], [0xff; 3]];
+        // the first entry is transparent, the second half transparent and the others opaque
+        let transparency = [0, 0x80];
+        let expected = [[0; 3], [0, 0x80, 0], [0, 0, 0xff], [0xff; 3]];
+        for (depth, bits) in [
+            (BitDepth::One, 1),
+            (BitDepth::Two, 2),
+            (BitDepth::Four, 4),
+            (BitDepth::Eight, 8),
+        ] {
+            let entries = palette.len().min(1 << bits);
+            let indices = (0..entries as u8).collect::<Vec<_>>();
+            let data = if bits == 8 {
+                indices
+            } else {
+                pack(&indices, bits)
+            };
+            let png = encode_row(
+                entries as u32,
+                ColorType::Indexed,
+                depth,
+                &data,
+                |encoder| {
+                    encoder.set_palette(
+                        palette[..entries].as_flattened().to_vec(),
+                    );
+                    encoder.set_trns(transparency.to_vec());
+                },
+            );
+            let image = RgbImage::decode(&png).unwrap();
+            assert_eq!(row(&image, 0), expected[..entries], "{bits} bits");
+        }
+    }
+
+    #[test]
+    fn decode_alpha_onto_black() {
+        let png = encode_row(
+            3,
+            ColorType::Rgba,
+            BitDepth::Eight,
+            &[
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x80, 0xff, 0xff,
+                0xff, 0,
+            ],
+            |_| {},
+        );
+        let image = RgbImage::decode(&png).unwrap();
+        assert_eq!(row(&image, 0), [[0xff; 3], [0x80; 3], [0; 3]]);
+
+        let png = encode_row(
+            3,
+            ColorType::GrayscaleAlpha,
+            BitDepth::Eight,
+            &[200, 0xff, 200, 0x80, 200, 0],
+            |_| {},
+        );
+        let image = RgbImage::decode(&png).unwrap();
+        assert_eq!(row(&image, 0), gray(&[200, 100, 0]));
+    }
+
+    #[test]
+    fn decode_16bit_color_and_alpha() {
+        let png = encode_row(
+            2,
+            ColorType::Rgb,
+            BitDepth::Sixteen,
+            &[0x12, 0x34, 0xab, 0xcd, 0xff, 0xff, 0, 0, 0x80, 0, 0, 0xff],
+            |_| {},
+        );
+        let image = RgbImage::decode(&png).unwrap();
+        assert_eq!(row(&image, 0), [[0x12, 0xab, 0xff], [0, 0x80, 0]]);
+
+        let png = encode_row(
+            2,
+            ColorType::Rgba,
+            BitDepth::Sixteen,
+            &[
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x80, 0x00, //
+                0xff, 0xff, 0, 0, 0, 0, 0xff, 0xff,
+            ],
+            |_| {},
+        );
+        let image = RgbImage::decode(&png).unwrap();
+        assert_eq!(row(&image, 0), [[0x80; 3], [0xff, 0, 0]]);
+
+        let png = encode_row(
+            2,
+            ColorType::GrayscaleAlpha,
+            BitDepth::Sixteen,
+            &[0xc8, 0, 0xff, 0xff, 0xc8, 0, 0, 0],
+            |_| {},
+        );
+        let image = RgbImage::decode(&png).unwrap();
+        assert_eq!(row(&image, 0), gray(&[200, 0]));
+    }
+
+    #[test]
+    fn decode_transparent_color_key() {
+        let png = encode_row(
+            2,
+            ColorType::Rgb,
+            BitDepth::Eight,
+            &[0xff, 0, 0, 0, 0xff, 0],
+            |encoder| encoder.set_trns(vec![0, 0xff, 0, 0, 0, 0]),
+        );
+        let image = RgbImage::decode(&png).unwrap();
+        assert_eq!(row(&image, 0), [[0; 3], [0, 0xff, 0]]);
+
+        let png = encode_row(
+            4,
+            ColorType::Grayscale,
+            BitDepth::Two,
+            &pack(&[0, 1, 2, 3], 2),
+            |encoder| encoder.set_trns(vec![0, 2]),
+        );
+        let image = RgbImage::decode(&png).unwrap();
+        assert_eq!(row(&image, 0), gray(&[0, 85, 0, 255]));
+    }
+
+    #[test]
+    fn decode_interlaced() {
+        // 3x3 grayscale with Adam7, the encoder only writes images without interlacing
+        let mut png = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+        // bit depth 8, grayscale, default compression and filter, Adam7 interlacing
+        let header = [0, 0, 0, 3, 0, 0, 0, 3, 8, 0, 0, 0, 1];
+        write_chunk(&mut png, b"IHDR", &header);
+        // pixel (x, y) has the value 10 * (3 * y + x), every scanline has the filter type none
+        let passes: [&[u8]; 7] = [
+            &[0, 0],          // pass 1: (0, 0)
+            &[],              // pass 2: no pixels in an image this small
+            &[],              // pass 3: none either
+            &[0, 20],         // pass 4: (2, 0)
+            &[0, 60, 80],     // pass 5: (0, 2) and (2, 2)
+            &[0, 10, 0, 70],  // pass 6: (1, 0) and (1, 2), one per row
+            &[0, 30, 40, 50], // pass 7: the middle row
+        ];
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        for pass in passes {
+            encoder.write_all(pass).unwrap();
+        }
+        write_chunk(&mut png, b"IDAT", &encoder.finish().unwrap());
+        write_chunk(&mut png, b"IEND", &[]);
+
+        let image = RgbImage::decode(&png).unwrap();
+
+        assert_eq!(row(&image, 0), gray(&[0, 10, 20]));
+        assert_eq!(row(&image, 1), gray(&[30, 40, 50]));
+        assert_eq!(row(&image, 2), gray(&[60, 70, 80]));
+    }
+
+    #[test]
+    fn decode_rejects_wrong_checksum() {
+        let mut png = RgbImage::new(2, 2).encode();
+        // the last byte of the IHDR checksum
+        png[8 + 8 + 13 + 3] ^= 1;
+
+        assert!(RgbImage::decode(&png).is_err());
+    }
+
+    #[test]
+    fn decode_rejects_truncated() {
+        let png = RgbImage::new(2, 2).encode();
+        for length in [0, 8, 20, png.len() - 14] {
+            assert!(RgbImage::decode(&png[..length]).is_err(), "{length}");
+        }
+        assert!(RgbImage::decode(&png[1..]).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use clap::ValueEnum;
+use log::info;
+use servicepoint::{
+    Bitmap, BitmapCommand, Brightness, Cp437Grid, Cp437GridCommand,
+    GlobalBrightnessCommand, Grid, Origin, PIXEL_HEIGHT, PIXEL_WIDTH,
+    TILE_HEIGHT, TILE_WIDTH,
+};
+use servicepoint_simulator::{snapshot::Snapshot, Simulator};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const CLOCK_INTERVAL: Duration = Duration::from_secs(1);
+const LIFE_INTERVAL: Duration = Duration::from_millis(100);
+/// Start over with a new random field after this many generations, even if the old one is still alive.
+const LIFE_MAX_GENERATIONS: usize = 3000;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScreensaverKind {
+    /// the current time, in UTC or with the offset configured with --clock-utc-offset
+    Clock,
+    /// Conway's game of life
+    Life,
+    /// the text configured with --screensaver-text
+    Text,
+}
+
+/// Shows something on the display after no valid packet arrived for a while.
+///
+/// The screensaver draws onto the shared display.
+/// The display content from before the screensaver started is restored when
+/// the next valid packet arrives.
+#[derive(Debug)]
+pub struct Screensaver {
+    kind: ScreensaverKind,
+    text: String,
+    /// minutes added to UTC for the clock, there is no time zone database to follow daylight saving time
+    utc_offset: i32,
+    idle_timeout: Duration,
+    running: Option<Running>,
+}
+
+#[derive(Debug)]
+struct Running {
+    saved: Snapshot,
+    /// `None` once there is nothing left to draw
+    next_frame: Option<Instant>,
+    life: Bitmap,
+    generation: usize,
+}
+
+impl Screensaver {
+    pub fn new(
+        kind: ScreensaverKind,
+        text: String,
+        utc_offset: i32,
+        idle_timeout: Duration,
+    ) -> Self {
+        Self {
+            kind,
+            text,
+            utc_offset,
+            idle_timeout,
+            running: None,
+        }
+    }
+
+    /// Starts the screensaver or draws its next frame when due.
+    ///
+    /// Returns whether the display changed.
+    pub fn tick(
+        &mut self,
+        simulator: &Simulator,
+        last_packet: Instant,
+    ) -> bool {
+        self.tick_at(simulator, last_packet, Instant::now())
+    }
+
+    fn tick_at(
+        &mut self,
+        simulator: &Simulator,
+        last_packet: Instant,
+        now: Instant,
+    ) -> bool {
+        let running = match &mut self.running {
+            Some(running) => running,
+            None if now >= last_packet + self.idle_timeout => {
+                info!(
+                    "no packets for {:?}, starting {:?} screensaver",
+                    self.idle_timeout, self.kind
+                );
+                let saved = simulator.snapshot();
+                let _ = simulator.execute(
+                    GlobalBrightnessCommand {
+                        brightness: Brightness::MAX,
+                    }
+                    .into(),
+                );
+                self.running.insert(Running {
+                    saved,
+                    next_frame: Some(now),
+                    life: Bitmap::max_sized(),
+                    generation: LIFE_MAX_GENERATIONS,
+                })
+            }
+            None => return false,
+        };
+
+        if !running
+            .next_frame
+            .is_some_and(|next_frame| now >= next_frame)
+        {
+            return false;
+        }
+        match self.kind {
+            ScreensaverKind::Clock => {
+                running.next_frame = Some(now + CLOCK_INTERVAL);
+                show_text(
+                    simulator,
+                    &clock_text(SystemTime::now(), self.utc_offset),
+                );
+            }
+            ScreensaverKind::Life => {
+                running.next_frame = Some(now + LIFE_INTERVAL);
+                running.step_life();
+                let _ = simulator
+                    .execute(BitmapCommand::from(running.life.clone()).into());
+            }
+            ScreensaverKind::Text => {
+                // the text does not change, so it only has to be drawn once
+                running.next_frame = None;
+                show_text(simulator, &self.text);
+            }
+        }
+        true
+    }
+
+    /// When [Self::tick] has something to do next, if ever.
+    pub fn next_tick(&self, last_packet: Instant) -> Option<Instant> {
+        match &self.running {
+            Some(running) => running.next_frame,
+            None => Some(last_packet + self.idle_timeout),
+        }
+    }
+
+    /// Stops the screensaver, if it is running, and restores the previous display content.
+    ///
+    /// Returns whether the screensaver was running.
+    pub fn wake(&mut self, simulator: &Simulator) -> bool {
+        let Some(running) = self.running.take() else {
+            return false;
+        };
+        info!("packet received, stopping screensaver");
+        simulator.restore(running.saved);
+        true
+    }
+}
+
+impl Running {
+    fn step_life(&mut self) {
+        if self.generation >= LIFE_MAX_GENERATIONS {
+            self.generation = 0;
+            for y in 0..PIXEL_HEIGHT {
+                for x in 0..PIXEL_WIDTH {
+                    self.life.set(x, y, fastrand::u8(..4) == 0);
+                }
+            }
+            return;
+        }
+
+        let mut next = Bitmap::max_sized();
+        for y in 0..PIXEL_HEIGHT {
+            for x in 0..PIXEL_WIDTH {
+                let neighbors = self.living_neighbors(x, y);
+                let alive = matches!(
+                    (self.life.get(x, y), neighbors),
+                    (true, 2) | (_, 3)
+                );
+                next.set(x, y, alive);
+            }
+        }
+        // a field that stopped changing is boring to look at
+        self.generation = if next == self.life {
+            LIFE_MAX_GENERATIONS
+        } else {
+            self.generation + 1
+        };
+        self.life = next;
+    }
+
+    /// Counts the living cells around a cell, wrapping around the edges.
+    fn living_neighbors(&self, x: usize, y: usize) -> usize {
+        let mut count = 0;
+        for dy in [PIXEL_HEIGHT - 1, 0, 1] {
+            for dx in [PIXEL_WIDTH - 1, 0, 1] {
+                if (dx, dy) == (0, 0) {
+                    continue;
+                }
+                let neighbor_x = (x + dx) % PIXEL_WIDTH;
+                let neighbor_y = (y + dy) % PIXEL_HEIGHT;
+                count += self.life.get(neighbor_x, neighbor_y) as usize;
+            }
+        }
+        count
+    }
+}
+
+/// Replaces the display content with the lines of the text centered on it.
+fn show_text(simulator: &Simulator, text: &str) {
+    let mut grid = Cp437Grid::new(TILE_WIDTH, TILE_HEIGHT);
+    let lines = text.lines().take(TILE_HEIGHT).collect::<Vec<_>>();
+    let start_y = (TILE_HEIGHT - lines.len()) / 2;
+    for (y, line) in (start_y..).zip(lines) {
+        // characters outside of ASCII cannot be shown with the CP437 font as they are
+        let line = line
+            .chars()
+            .map(|char| if char.is_ascii() { char as u8 } else { b'?' })
+            .take(TILE_WIDTH)
+            .collect::<Vec<_>>();
+        let start_x = (TILE_WIDTH - line.len()) / 2;
+        for (x, char) in (start_x..).zip(line) {
+            grid.set(x, y, char);
+        }
+    }
+
+    // the grid covers the whole display, so nothing of the previous content is left
+    let _ = simulator.execute(
+        Cp437GridCommand {
+            grid,
+            origin: Origin::ZERO,
+        }
+        .into(),
+    );
+}
+
+/// Formats the time of day, `utc_offset` minutes ahead of UTC.
+fn clock_text(now: SystemTime, utc_offset: i32) -> String {
+    let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+        as i64
+        + utc_offset as i64 * 60;
+    let seconds = seconds.rem_euclid(24 * 3600);
+    let (hours, minutes, seconds) =
+        (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    let zone = match utc_offset {
+        0 => String::from("UTC"),
+        offset => format!(
+            "UTC{}{:02}:{:02}",
+            if offset < 0 { '-' } else { '+' },
+            offset.abs() / 60,
+            offset.abs() % 60
+        ),
+    };
+    format!("{hours:02}:{minutes:02}:{seconds:02}\n{zone}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn screensaver(kind: ScreensaverKind) -> Screensaver {
+        Screensaver::new(kind, String::from("idle"), 0, IDLE_TIMEOUT)
+    }
+
+    /// Turns on a pixel and dims the display, so the content is easy to recognize.
+    fn draw_content(simulator: &Simulator) {
+        let mut bitmap = Bitmap::max_sized();
+        bitmap.set(0, 0, true);
+        let _ = simulator.execute(BitmapCommand::from(bitmap).into());
+        let _ = simulator.execute(
+            GlobalBrightnessCommand {
+                brightness: Brightness::MIN,
+            }
+            .into(),
+        );
+    }
+
+    #[test]
+    fn starts_after_idle_timeout() {
+        let simulator = Simulator::default();
+        draw_content(&simulator);
+        let before = simulator.snapshot();
+        let mut screensaver = screensaver(ScreensaverKind::Text);
+        let last_packet = Instant::now();
+        assert_eq!(
+            screensaver.next_tick(last_packet),
+            Some(last_packet + IDLE_TIMEOUT)
+        );
+
+        let almost = last_packet + IDLE_TIMEOUT - Duration::from_millis(1);
+        assert!(!screensaver.tick_at(&simulator, last_packet, almost));
+        assert_eq!(simulator.snapshot(), before);
+
+        let idle = last_packet + IDLE_TIMEOUT;
+        assert!(screensaver.tick_at(&simulator, last_packet, idle));
+        assert_ne!(simulator.snapshot(), before);
+        assert!(simulator
+            .brightness()
+            .iter()
+            .all(|brightness| *brightness == Brightness::MAX));
+
+        // the text is only drawn once
+        assert_eq!(screensaver.next_tick(last_packet), None);
+        assert!(!screensaver.tick_at(&simulator, last_packet, idle));
+    }
+
+    #[test]
+    fn draws_next_frame_when_due() {
+        let simulator = Simulator::default();
+        let mut screensaver = screensaver(ScreensaverKind::Life);
+        let last_packet = Instant::now();
+        let idle = last_packet + IDLE_TIMEOUT;
+        assert!(screensaver.tick_at(&simulator, last_packet, idle));
+        assert_eq!(
+            screensaver.next_tick(last_packet),
+            Some(idle + LIFE_INTERVAL)
+        );
+        assert!(!screensaver.tick_at(&simulator, last_packet, idle));
+        assert!(screensaver.tick_at(
+            &simulator,
+            last_packet,
+            idle + LIFE_INTERVAL
+        ));
+    }
+
+    #[test]
+    fn wake_restores_saved_state() {
+        let simulator = Simulator::default();
+        draw_content(&simulator);
+        let before = simulator.snapshot();
+        let mut screensaver = screensaver(ScreensaverKind::Text);
+        assert!(!screensaver.wake(&simulator));
+
+        let last_packet = Instant::now();
+        screensaver.tick_at(
+            &simulator,
+            last_packet,
+            last_packet + IDLE_TIMEOUT,
+        );
+        assert!(screensaver.wake(&simulator));
+        assert_eq!(simulator.snapshot(), before);
+
+        // the idle timeout starts over with the next packet
+        assert!(!screensaver.wake(&simulator));
+        let next_packet = last_packet + IDLE_TIMEOUT;
+        assert_eq!(
+            screensaver.next_tick(next_packet),
+            Some(next_packet + IDLE_TIMEOUT)
+        );
+    }
+
+    #[test]
+    fn clock_with_utc_offset() {
+        let time = UNIX_EPOCH + Duration::from_secs(12 * 3600 + 34 * 60 + 56);
+        assert_eq!(clock_text(time, 0), "12:34:56\nUTC");
+        assert_eq!(clock_text(time, 90), "14:04:56\nUTC+01:30");
+        assert_eq!(clock_text(time, -13 * 60), "23:34:56\nUTC-13:00");
+    }
+}
@@ -0,0 +1,289 @@
+use crate::{
+    compositor::Compositor, defects::Defects, front_buffer::FrontBuffer,
+};
+use log::info;
+use servicepoint_simulator::Simulator;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+
+/// The display state of a single client when running with isolated clients.
+///
+/// A client is identified by its IP address, so it keeps its display
+/// when it sends from a new source port, e.g. with a new socket for every packet.
+#[derive(Debug)]
+pub struct SenderDisplay {
+    pub source: IpAddr,
+    pub simulator: Simulator,
+    pub front: FrontBuffer,
+    last_packet: Mutex<LastPacket>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LastPacket {
+    received: Instant,
+    from: SocketAddr,
+}
+
+/// All virtual displays, in the order their senders were first seen.
+///
+/// Every sender costs a display and is composited for every frame,
+/// so beyond the maximum number of senders the one idle the longest is forgotten
+/// to make room for a new one.
+#[derive(Debug)]
+pub struct SenderDisplays {
+    senders: RwLock<Senders>,
+    max_senders: usize,
+    /// the simulated hardware defects of every virtual display
+    defects: Option<Arc<Defects>>,
+}
+
+#[derive(Debug, Default)]
+struct Senders {
+    by_source: HashMap<IpAddr, Arc<SenderDisplay>>,
+    /// the same displays, in the order their senders were first seen
+    ordered: Vec<Arc<SenderDisplay>>,
+}
+
+/// Where the commands of each sender are executed.
+#[derive(Debug)]
+pub enum ClientDisplays<'t> {
+    /// on the shared display
+    Shared,
+    /// on the virtual display of the sender
+    Isolated(&'t SenderDisplays),
+    /// on the virtual display of the sender, which is then composited onto the shared display
+    Composited(Compositor<'t>),
+}
+
+impl<'t> ClientDisplays<'t> {
+    /// The virtual displays of the senders, unless all senders share one display.
+    pub fn senders(&self) -> Option<&'t SenderDisplays> {
+        match self {
+            ClientDisplays::Shared => None,
+            ClientDisplays::Isolated(senders) => Some(senders),
+            ClientDisplays::Composited(compositor) => {
+                Some(compositor.senders())
+            }
+        }
+    }
+}
+
+impl SenderDisplay {
+    fn new(
+        from: SocketAddr,
+        shared: &Simulator,
+        defects: Option<Arc<Defects>>,
+        now: Instant,
+    ) -> Self {
+        let simulator = shared.with_same_fonts();
+        Self {
+            source: from.ip(),
+            front: FrontBuffer::new(simulator.snapshot(), defects),
+            simulator,
+            last_packet: Mutex::new(LastPacket {
+                received: now,
+                from,
+            }),
+        }
+    }
+
+    /// The address and port the last packet of the sender came from.
+    pub fn address(&self) -> SocketAddr {
+        self.last_packet.lock().unwrap().from
+    }
+
+    fn seen(&self, from: SocketAddr, now: Instant) {
+        *self.last_packet.lock().unwrap() = LastPacket {
+            received: now,
+            from,
+        };
+    }
+}
+
+impl SenderDisplays {
+    /// `max_senders` has to be at least one.
+    pub fn new(max_senders: usize, defects: Option<Arc<Defects>>) -> Self {
+        assert!(max_senders > 0, "there has to be room for a sender");
+        Self {
+            senders: RwLock::default(),
+            max_senders,
+            defects,
+        }
+    }
+
+    /// Returns the virtual display of the sender, creating it with the fonts of the shared display if needed.
+    pub fn get_or_insert(
+        &self,
+        from: SocketAddr,
+        shared: &Simulator,
+    ) -> Arc<SenderDisplay> {
+        self.get_or_insert_at(from, shared, Instant::now())
+    }
+
+    fn get_or_insert_at(
+        &self,
+        from: SocketAddr,
+        shared: &Simulator,
+        now: Instant,
+    ) -> Arc<SenderDisplay> {
+        if let Some(sender) = self.get(from.ip()) {
+            sender.seen(from, now);
+            return sender;
+        }
+
+        let mut senders = self.senders.write().unwrap();
+        // another thread may have added the sender in the meantime
+        if let Some(sender) = senders.by_source.get(&from.ip()) {
+            sender.seen(from, now);
+            return sender.clone();
+        }
+        if senders.ordered.len() >= self.max_senders {
+            senders.remove_idle(self.max_senders);
+        }
+        info!("creating virtual display for new sender {}", from.ip());
+        let sender = Arc::new(SenderDisplay::new(
+            from,
+            shared,
+            self.defects.clone(),
+            now,
+        ));
+        senders.by_source.insert(sender.source, sender.clone());
+        senders.ordered.push(sender.clone());
+        sender
+    }
+
+    pub fn get(&self, source: IpAddr) -> Option<Arc<SenderDisplay>> {
+        self.senders.read().unwrap().by_source.get(&source).cloned()
+    }
+
+    pub fn get_index(&self, index: usize) -> Option<Arc<SenderDisplay>> {
+        self.senders.read().unwrap().ordered.get(index).cloned()
+    }
+
+    /// The position of the sender in the order senders were first seen.
+    pub fn index_of(&self, source: IpAddr) -> Option<usize> {
+        self.senders
+            .read()
+            .unwrap()
+            .ordered
+            .iter()
+            .position(|sender| sender.source == source)
+    }
+
+    pub fn all(&self) -> Vec<Arc<SenderDisplay>> {
+        self.senders.read().unwrap().ordered.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.senders.read().unwrap().ordered.len()
+    }
+}
+
+impl Senders {
+    /// Forgets the sender that sent nothing for the longest time.
+    fn remove_idle(&mut self, max_senders: usize) {
+        let Some(index) = (0..self.ordered.len()).min_by_key(|&index| {
+            self.ordered[index].last_packet.lock().unwrap().received
+        }) else {
+            return;
+        };
+        let sender = self.ordered.remove(index);
+        self.by_source.remove(&sender.source);
+        info!(
+            "forgetting virtual display of {}, there are already {max_senders} senders",
+            sender.source
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::Ipv4Addr, time::Duration};
+
+    const MAX_SENDERS: usize = 4;
+
+    fn source(index: u8) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, index).into(), 2342)
+    }
+
+    fn sources(senders: &SenderDisplays) -> Vec<u8> {
+        senders
+            .all()
+            .iter()
+            .map(|sender| match sender.source {
+                IpAddr::V4(ip) => ip.octets()[3],
+                IpAddr::V6(_) => unreachable!("only IPv4 senders"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn get_or_insert_returns_same_display() {
+        let shared = Simulator::default();
+        let senders = SenderDisplays::new(MAX_SENDERS, None);
+        let first = senders.get_or_insert(source(1), &shared);
+        let second = senders.get_or_insert(source(2), &shared);
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(
+            &first,
+            &senders.get_or_insert(source(1), &shared)
+        ));
+        assert!(Arc::ptr_eq(&second, &senders.get(source(2).ip()).unwrap()));
+        assert!(senders.get(source(3).ip()).is_none());
+        assert_eq!(senders.len(), 2);
+    }
+
+    #[test]
+    fn new_source_port_keeps_display() {
+        let shared = Simulator::default();
+        let senders = SenderDisplays::new(MAX_SENDERS, None);
+        let first = senders.get_or_insert(source(1), &shared);
+        let other_port = SocketAddr::new(source(1).ip(), 4000);
+        let second = senders.get_or_insert(other_port, &shared);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(senders.len(), 1);
+        assert_eq!(first.address(), other_port);
+    }
+
+    #[test]
+    fn keeps_order_first_seen() {
+        let shared = Simulator::default();
+        let senders = SenderDisplays::new(MAX_SENDERS, None);
+        for index in [5, 3, 5, 9, 3] {
+            senders.get_or_insert(source(index), &shared);
+        }
+        assert_eq!(sources(&senders), [5, 3, 9]);
+        assert_eq!(senders.get_index(1).unwrap().source, source(3).ip());
+        assert!(senders.get_index(3).is_none());
+        assert_eq!(senders.index_of(source(9).ip()), Some(2));
+        assert_eq!(senders.index_of(source(1).ip()), None);
+    }
+
+    #[test]
+    fn forgets_idle_sender_when_full() {
+        let shared = Simulator::default();
+        let senders = SenderDisplays::new(MAX_SENDERS, None);
+        let start = Instant::now();
+        for index in 0..MAX_SENDERS as u8 {
+            senders.get_or_insert_at(source(index), &shared, start);
+        }
+        // everyone but sender 1 sends again
+        let later = start + Duration::from_secs(1);
+        for index in (0..MAX_SENDERS as u8).filter(|index| *index != 1) {
+            senders.get_or_insert_at(source(index), &shared, later);
+        }
+
+        senders.get_or_insert_at(source(100), &shared, later);
+        assert_eq!(senders.len(), MAX_SENDERS);
+        assert!(senders.get(source(1).ip()).is_none());
+        let mut expected = vec![0];
+        expected.extend(2..MAX_SENDERS as u8);
+        expected.push(100);
+        assert_eq!(sources(&senders), expected);
+    }
+}
@@ -0,0 +1,334 @@
+use crate::{
+    command_executor::{
+        CommandExecute, CommandExecutionContext, ExecutionResult,
+    },
+    cp437_font::Cp437Font,
+    font_renderer::FontRenderer8x8,
+    snapshot::Snapshot,
+};
+use log::warn;
+use servicepoint::{
+    Bitmap, BrightnessGrid, Packet, TryFromPacketError, TypedCommand,
+    TILE_HEIGHT, TILE_WIDTH,
+};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
+
+/// A display that executes commands in memory, e.g. for checking what a client sends in tests.
+///
+/// ```
+/// use servicepoint::{ClearCommand, TypedCommand};
+/// use servicepoint_simulator::{ExecutionResult, Simulator};
+///
+/// let simulator = Simulator::default();
+/// let result = simulator.execute(TypedCommand::Clear(ClearCommand));
+/// assert_eq!(result, ExecutionResult::Success);
+/// assert!(simulator.bitmap().iter().all(|pixel| !pixel));
+/// ```
+#[derive(Debug)]
+pub struct Simulator {
+    display: RwLock<Bitmap>,
+    luma: RwLock<BrightnessGrid>,
+    cp437_font: Arc<Cp437Font>,
+    font_renderer: Arc<FontRenderer8x8>,
+    /// whether the deprecated BitmapLegacy command is ignored instead of failing
+    legacy: bool,
+    warned_legacy: AtomicBool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FeedPacketError {
+    #[error("could not read packet with length {length}: {source}")]
+    TooShort {
+        length: usize,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("could not read command from packet: {0:?}")]
+    InvalidCommand(#[from] TryFromPacketError),
+}
+
+impl Simulator {
+    /// Creates a simulator with an empty display that renders text with the provided font.
+    pub fn new(font_renderer: FontRenderer8x8) -> Self {
+        Self {
+            display: RwLock::new(Bitmap::max_sized()),
+            luma: RwLock::new(BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT)),
+            cp437_font: Arc::new(Cp437Font::default()),
+            font_renderer: Arc::new(font_renderer),
+            legacy: false,
+            warned_legacy: AtomicBool::new(false),
+        }
+    }
+
+    /// Ignores the deprecated BitmapLegacy command like the real display does,
+    /// instead of failing to execute it.
+    ///
+    /// A deprecation warning is logged the first time the command is ignored.
+    pub fn with_legacy(mut self, legacy: bool) -> Self {
+        self.legacy = legacy;
+        self
+    }
+
+    /// Creates a simulator with an empty display that renders text and handles legacy commands like this one,
+    /// without loading the fonts again, e.g. for a virtual display per client.
+    pub fn with_same_fonts(&self) -> Self {
+        Self {
+            display: RwLock::new(Bitmap::max_sized()),
+            luma: RwLock::new(BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT)),
+            cp437_font: Arc::clone(&self.cp437_font),
+            font_renderer: Arc::clone(&self.font_renderer),
+            legacy: self.legacy,
+            warned_legacy: AtomicBool::new(false),
+        }
+    }
+
+    pub fn execute(&self, command: TypedCommand) -> ExecutionResult {
+        #[allow(deprecated)]
+        if self.legacy && matches!(command, TypedCommand::BitmapLegacy(_)) {
+            if !self.warned_legacy.swap(true, Ordering::Relaxed) {
+                warn!("ignoring the deprecated BitmapLegacy command like the real display, it may stop working");
+            }
+            return ExecutionResult::Success;
+        }
+        command.execute(&self.context())
+    }
+
+    /// Parses a packet like it was received via UDP and executes the contained command.
+    pub fn feed_packet(
+        &self,
+        packet: &[u8],
+    ) -> Result<ExecutionResult, FeedPacketError> {
+        let length = packet.len();
+        let packet = Packet::try_from(packet).map_err(|err| {
+            FeedPacketError::TooShort {
+                length,
+                source: Box::new(err),
+            }
+        })?;
+        let command = TypedCommand::try_from(packet)?;
+        Ok(self.execute(command))
+    }
+
+    /// Returns a copy of the pixels currently shown.
+    pub fn bitmap(&self) -> Bitmap {
+        self.display.read().unwrap().clone()
+    }
+
+    /// Returns a copy of the current brightness of each tile.
+    pub fn brightness(&self) -> BrightnessGrid {
+        self.luma.read().unwrap().clone()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.context().snapshot()
+    }
+
+    /// Replaces the whole state with the one from the snapshot.
+    pub fn restore(&self, snapshot: Snapshot) {
+        self.context().restore(snapshot);
+    }
+
+    fn context(&self) -> CommandExecutionContext<'_> {
+        CommandExecutionContext::new(
+            &self.display,
+            &self.luma,
+            &self.cp437_font,
+            &self.font_renderer,
+        )
+    }
+}
+
+impl Default for Simulator {
+    /// Creates a simulator that renders text with the default system monospace font.
+    fn default() -> Self {
+        Self::new(FontRenderer8x8::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use servicepoint::{
+        BitmapCommand, Brightness, BrightnessGridCommand, ClearCommand,
+        CompressionCode, GlobalBrightnessCommand, Grid, HardResetCommand,
+        Origin, PIXEL_WIDTH,
+    };
+
+    fn bitmap_command(x: usize, y: usize) -> BitmapCommand {
+        let mut bitmap = Bitmap::new(8, 2).unwrap();
+        bitmap.set(0, 0, true);
+        bitmap.set(7, 1, true);
+        BitmapCommand {
+            bitmap,
+            origin: Origin::new(x, y),
+            compression: CompressionCode::Uncompressed,
+        }
+    }
+
+    fn pixels_on(bitmap: &Bitmap) -> usize {
+        bitmap.iter().filter(|pixel| **pixel).count()
+    }
+
+    #[test]
+    fn execute_draws_bitmap() {
+        let simulator = Simulator::default();
+        let result = simulator.execute(bitmap_command(8, 1).into());
+
+        assert_eq!(result, ExecutionResult::Success);
+        let bitmap = simulator.bitmap();
+        assert!(bitmap.get(8, 1));
+        assert!(bitmap.get(15, 2));
+        assert_eq!(pixels_on(&bitmap), 2);
+    }
+
+    #[test]
+    fn execute_clear() {
+        let simulator = Simulator::default();
+        assert_eq!(
+            simulator.execute(bitmap_command(0, 0).into()),
+            ExecutionResult::Success
+        );
+
+        let result = simulator.execute(TypedCommand::Clear(ClearCommand));
+
+        assert_eq!(result, ExecutionResult::Success);
+        assert_eq!(pixels_on(&simulator.bitmap()), 0);
+    }
+
+    #[test]
+    fn execute_out_of_bounds_fails() {
+        let simulator = Simulator::default();
+        let result = simulator.execute(bitmap_command(PIXEL_WIDTH, 0).into());
+        assert_eq!(result, ExecutionResult::Failure);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn execute_legacy_only_when_enabled() {
+        let command =
+            TypedCommand::BitmapLegacy(servicepoint::BitmapLegacyCommand);
+        let simulator = Simulator::default();
+        assert_eq!(
+            simulator.execute(command.clone()),
+            ExecutionResult::Failure
+        );
+
+        let simulator = Simulator::default().with_legacy(true);
+        let _ = simulator.execute(bitmap_command(0, 0).into());
+        for _ in 0..2 {
+            assert_eq!(
+                simulator.execute(command.clone()),
+                ExecutionResult::Success
+            );
+        }
+        assert_eq!(pixels_on(&simulator.bitmap()), 2);
+        assert_eq!(
+            simulator.with_same_fonts().execute(command),
+            ExecutionResult::Success
+        );
+    }
+
+    #[test]
+    fn execute_hard_reset_shuts_down() {
+        let simulator = Simulator::default();
+        let result = simulator.execute(HardResetCommand.into());
+        assert_eq!(result, ExecutionResult::Shutdown);
+    }
+
+    #[test]
+    fn brightness_of_tiles() {
+        let simulator = Simulator::default();
+        let _ = simulator.execute(
+            GlobalBrightnessCommand {
+                brightness: Brightness::MAX,
+            }
+            .into(),
+        );
+        let mut grid = BrightnessGrid::new(2, 1);
+        grid.fill(Brightness::MIN);
+        let _ = simulator.execute(
+            BrightnessGridCommand {
+                grid,
+                origin: Origin::new(3, 4),
+            }
+            .into(),
+        );
+
+        let brightness = simulator.brightness();
+        assert_eq!(brightness.width(), TILE_WIDTH);
+        assert_eq!(brightness.height(), TILE_HEIGHT);
+        assert_eq!(brightness.get(3, 4), Brightness::MIN);
+        assert_eq!(brightness.get(4, 4), Brightness::MIN);
+        assert_eq!(brightness.get(5, 4), Brightness::MAX);
+        assert_eq!(brightness.get(3, 5), Brightness::MAX);
+    }
+
+    #[test]
+    fn bitmap_and_brightness_are_copies() {
+        let simulator = Simulator::default();
+        let brightness = simulator.brightness();
+
+        simulator.bitmap().fill(true);
+        simulator.brightness().fill(Brightness::MIN);
+
+        assert_eq!(pixels_on(&simulator.bitmap()), 0);
+        assert_eq!(simulator.brightness(), brightness);
+    }
+
+    #[test]
+    fn feed_packet_executes_command() {
+        let simulator = Simulator::default();
+        let mut command = bitmap_command(16, 3);
+        command.compression = CompressionCode::Zlib;
+        let packet = Vec::<u8>::from(Packet::try_from(command).unwrap());
+
+        let result = simulator.feed_packet(&packet).unwrap();
+
+        assert_eq!(result, ExecutionResult::Success);
+        let bitmap = simulator.bitmap();
+        assert!(bitmap.get(16, 3));
+        assert!(bitmap.get(23, 4));
+        assert_eq!(pixels_on(&bitmap), 2);
+    }
+
+    #[test]
+    fn feed_packet_too_short() {
+        let simulator = Simulator::default();
+        let error = simulator.feed_packet(&[0; 9]).unwrap_err();
+
+        assert!(matches!(error, FeedPacketError::TooShort { length: 9, .. }));
+        assert!(std::error::Error::source(&error).is_some());
+    }
+
+    #[test]
+    fn feed_packet_invalid_command() {
+        let simulator = Simulator::default();
+        let mut packet = Vec::<u8>::from(Packet::from(ClearCommand));
+        packet[..2].copy_from_slice(&[0xFF, 0xFF]);
+
+        let error = simulator.feed_packet(&packet).unwrap_err();
+
+        assert!(matches!(
+            error,
+            FeedPacketError::InvalidCommand(
+                TryFromPacketError::InvalidCommand(_)
+            )
+        ));
+    }
+
+    #[test]
+    fn same_fonts_but_own_display() {
+        let simulator = Simulator::default();
+        assert_eq!(
+            simulator.execute(bitmap_command(0, 0).into()),
+            ExecutionResult::Success
+        );
+
+        let other = simulator.with_same_fonts();
+
+        assert_eq!(pixels_on(&other.bitmap()), 0);
+        assert!(Arc::ptr_eq(&simulator.font_renderer, &other.font_renderer));
+    }
+}
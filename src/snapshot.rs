@@ -0,0 +1,355 @@
+use servicepoint::{
+    Bitmap, Brightness, BrightnessGrid, Grid, PIXEL_HEIGHT, PIXEL_WIDTH,
+    TILE_HEIGHT, TILE_WIDTH,
+};
+use std::{
+    fmt::{Display, Formatter},
+    fs,
+    path::Path,
+};
+
+const HEADER: &str = "servicepoint-snapshot 1";
+const PIXEL_ON: char = '#';
+const PIXEL_OFF: char = '.';
+
+/// The complete state of a display: its pixels and the brightness of each tile.
+///
+/// Stored as plain text, so snapshots can be edited by hand and compared with `diff`:
+///
+/// ```text
+/// servicepoint-snapshot 1
+/// <160 lines with 448 pixels each, '#' for on and '.' for off>
+/// <20 lines with 56 tile brightnesses each, as hex digits from 0 to b>
+/// ```
+///
+/// Loading a saved snapshot restores exactly the same state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    bitmap: Bitmap,
+    brightness: BrightnessGrid,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("could not access snapshot file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("line {line}: {message}")]
+    InvalidLine { line: usize, message: String },
+    #[error(
+        "the bitmap has {0}x{1} pixels, expected {PIXEL_WIDTH}x{PIXEL_HEIGHT}"
+    )]
+    WrongBitmapSize(usize, usize),
+    #[error("the brightness grid has {0}x{1} tiles, expected {TILE_WIDTH}x{TILE_HEIGHT}")]
+    WrongBrightnessSize(usize, usize),
+}
+
+impl Snapshot {
+    /// Fails unless the bitmap and the brightness grid have the size of the display.
+    pub fn new(
+        bitmap: Bitmap,
+        brightness: BrightnessGrid,
+    ) -> Result<Self, SnapshotError> {
+        if (bitmap.width(), bitmap.height()) != (PIXEL_WIDTH, PIXEL_HEIGHT) {
+            return Err(SnapshotError::WrongBitmapSize(
+                bitmap.width(),
+                bitmap.height(),
+            ));
+        }
+        if (brightness.width(), brightness.height())
+            != (TILE_WIDTH, TILE_HEIGHT)
+        {
+            return Err(SnapshotError::WrongBrightnessSize(
+                brightness.width(),
+                brightness.height(),
+            ));
+        }
+        Ok(Self { bitmap, brightness })
+    }
+
+    pub fn bitmap(&self) -> &Bitmap {
+        &self.bitmap
+    }
+
+    pub fn brightness(&self) -> &BrightnessGrid {
+        &self.brightness
+    }
+
+    pub fn into_parts(self) -> (Bitmap, BrightnessGrid) {
+        (self.bitmap, self.brightness)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SnapshotError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SnapshotError> {
+        let mut lines = text.lines().enumerate().map(|(index, line)| {
+            (index + 1, line.strip_suffix('\r').unwrap_or(line))
+        });
+        let mut next_line = |expected: &str| {
+            lines.next().ok_or_else(|| SnapshotError::InvalidLine {
+                line: text.lines().count() + 1,
+                message: format!("expected {expected}, got end of file"),
+            })
+        };
+
+        let (line, header) = next_line("header")?;
+        if header != HEADER {
+            return Err(invalid(line, format!("expected '{HEADER}'")));
+        }
+
+        let mut bitmap = Bitmap::max_sized();
+        for y in 0..PIXEL_HEIGHT {
+            let (line, row) = next_line("row of pixels")?;
+            check_length(line, row, PIXEL_WIDTH)?;
+            for (x, char) in row.chars().enumerate() {
+                let is_on = match char {
+                    PIXEL_ON => true,
+                    PIXEL_OFF => false,
+                    _ => {
+                        return Err(invalid(
+                            line,
+                            format!("invalid pixel '{char}'"),
+                        ))
+                    }
+                };
+                bitmap.set(x, y, is_on);
+            }
+        }
+
+        let mut brightness = BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT);
+        for y in 0..TILE_HEIGHT {
+            let (line, row) = next_line("row of tile brightnesses")?;
+            check_length(line, row, TILE_WIDTH)?;
+            for (x, char) in row.chars().enumerate() {
+                let value = char
+                    .to_digit(16)
+                    .and_then(|digit| Brightness::try_from(digit as u8).ok())
+                    .ok_or_else(|| {
+                        invalid(line, format!("invalid brightness '{char}'"))
+                    })?;
+                brightness.set(x, y, value);
+            }
+        }
+
+        if let Some((line, _)) = lines.find(|(_, line)| !line.is_empty()) {
+            return Err(invalid(line, String::from("unexpected content")));
+        }
+        Ok(Self { bitmap, brightness })
+    }
+}
+
+fn invalid(line: usize, message: String) -> SnapshotError {
+    SnapshotError::InvalidLine { line, message }
+}
+
+fn check_length(
+    line: usize,
+    row: &str,
+    expected: usize,
+) -> Result<(), SnapshotError> {
+    let length = row.chars().count();
+    if length != expected {
+        return Err(invalid(
+            line,
+            format!("expected {expected} characters, got {length}"),
+        ));
+    }
+    Ok(())
+}
+
+impl Display for Snapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{HEADER}")?;
+        for y in 0..PIXEL_HEIGHT {
+            let row = (0..PIXEL_WIDTH)
+                .map(|x| {
+                    if self.bitmap.get(x, y) {
+                        PIXEL_ON
+                    } else {
+                        PIXEL_OFF
+                    }
+                })
+                .collect::<String>();
+            writeln!(f, "{row}")?;
+        }
+        for y in 0..TILE_HEIGHT {
+            let row = (0..TILE_WIDTH)
+                .map(|x| format!("{:x}", u8::from(self.brightness.get(x, y))))
+                .collect::<String>();
+            writeln!(f, "{row}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> Snapshot {
+        let mut bitmap = Bitmap::max_sized();
+        for x in 0..PIXEL_WIDTH {
+            bitmap.set(x, x % PIXEL_HEIGHT, true);
+        }
+        bitmap.set(PIXEL_WIDTH - 1, PIXEL_HEIGHT - 1, true);
+        let mut brightness = BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT);
+        for y in 0..TILE_HEIGHT {
+            for x in 0..TILE_WIDTH {
+                let value =
+                    ((x + y) % (u8::from(Brightness::MAX) as usize + 1)) as u8;
+                brightness.set(x, y, Brightness::try_from(value).unwrap());
+            }
+        }
+        Snapshot::new(bitmap, brightness).unwrap()
+    }
+
+    /// Replaces one line of the formatted example, counting from 1.
+    fn example_with_line(line: usize, content: &str) -> String {
+        let text = example().to_string();
+        let mut lines = text.lines().map(str::to_owned).collect::<Vec<_>>();
+        lines[line - 1] = content.to_owned();
+        lines.join("\n")
+    }
+
+    fn assert_invalid(
+        text: &str,
+        expected_line: usize,
+        expected_message: &str,
+    ) {
+        match Snapshot::parse(text) {
+            Err(SnapshotError::InvalidLine { line, message }) => {
+                assert_eq!(line, expected_line, "{message}");
+                assert!(
+                    message.contains(expected_message),
+                    "'{message}' does not contain '{expected_message}'"
+                );
+            }
+            result => panic!("expected an invalid line, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_formatted() {
+        let snapshot = example();
+        assert_eq!(Snapshot::parse(&snapshot.to_string()).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn parse_windows_line_endings() {
+        let text = example().to_string().replace('\n', "\r\n");
+        assert_eq!(Snapshot::parse(&text).unwrap(), example());
+    }
+
+    #[test]
+    fn parse_trailing_empty_lines() {
+        let text = format!("{}\n\n", example());
+        assert_eq!(Snapshot::parse(&text).unwrap(), example());
+    }
+
+    #[test]
+    fn parse_wrong_header() {
+        assert_invalid(
+            &example_with_line(1, "servicepoint-snapshot 2"),
+            1,
+            HEADER,
+        );
+    }
+
+    #[test]
+    fn parse_wrong_dimensions() {
+        let short_row = PIXEL_OFF.to_string().repeat(PIXEL_WIDTH - 1);
+        assert_invalid(
+            &example_with_line(2, &short_row),
+            2,
+            "expected 448 characters, got 447",
+        );
+        let long_row = "0".repeat(TILE_WIDTH + 1);
+        assert_invalid(
+            &example_with_line(2 + PIXEL_HEIGHT, &long_row),
+            2 + PIXEL_HEIGHT,
+            "expected 56 characters, got 57",
+        );
+        // a row too many
+        let text = format!("{}{}\n", example(), "0".repeat(TILE_WIDTH));
+        assert_invalid(
+            &text,
+            2 + PIXEL_HEIGHT + TILE_HEIGHT,
+            "unexpected content",
+        );
+    }
+
+    #[test]
+    fn parse_invalid_pixel() {
+        let mut row = PIXEL_OFF.to_string().repeat(PIXEL_WIDTH - 1);
+        row.push('x');
+        assert_invalid(&example_with_line(10, &row), 10, "invalid pixel 'x'");
+    }
+
+    #[test]
+    fn parse_bad_brightness_digits() {
+        for digit in ['c', 'f', 'g', '-'] {
+            let mut row = "0".repeat(TILE_WIDTH - 1);
+            row.push(digit);
+            let line = 2 + PIXEL_HEIGHT + 5;
+            assert_invalid(
+                &example_with_line(line, &row),
+                line,
+                &format!("invalid brightness '{digit}'"),
+            );
+        }
+    }
+
+    #[test]
+    fn parse_truncated_rows() {
+        let text = example().to_string();
+        let lines = text.lines().collect::<Vec<_>>();
+
+        let truncated = lines[..1 + 20].join("\n");
+        assert_invalid(
+            &truncated,
+            22,
+            "expected row of pixels, got end of file",
+        );
+
+        let truncated = lines[..1 + PIXEL_HEIGHT + 3].join("\n");
+        assert_invalid(
+            &truncated,
+            PIXEL_HEIGHT + 5,
+            "expected row of tile brightnesses, got end of file",
+        );
+
+        assert_invalid("", 1, "expected header, got end of file");
+    }
+
+    #[test]
+    fn new_rejects_wrong_sizes() {
+        let brightness = BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT);
+        let result =
+            Snapshot::new(Bitmap::new(8, 1).unwrap(), brightness.clone());
+        assert!(
+            matches!(result, Err(SnapshotError::WrongBitmapSize(8, 1))),
+            "{result:?}"
+        );
+
+        let result = Snapshot::new(
+            Bitmap::max_sized(),
+            BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT + 1),
+        );
+        assert!(
+            matches!(
+                result,
+                Err(SnapshotError::WrongBrightnessSize(TILE_WIDTH, 21))
+            ),
+            "{result:?}"
+        );
+
+        let (bitmap, luma) = example().into_parts();
+        assert_eq!(Snapshot::new(bitmap, luma).unwrap(), example());
+    }
+}
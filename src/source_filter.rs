@@ -0,0 +1,457 @@
+use crate::packet_pipeline::DropReason;
+use log::warn;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+const DROP_LOG_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_TRACKED_SOURCES: usize = 4096;
+
+/// An IP address range in CIDR notation, e.g. `10.0.0.0/8` or `fe80::/10`.
+///
+/// A plain address is treated as a range containing only that address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+/// Decides which packets are processed based on their source address.
+#[derive(Debug)]
+pub struct SourceFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    rate_limit: Option<RateLimit>,
+    buckets: HashMap<IpAddr, TokenBucket>,
+    denied: usize,
+    rate_limited: usize,
+    last_drop_log: Instant,
+}
+
+/// Maximum average packets per second and burst size per source address.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl RateLimit {
+    /// Bursts default to one second worth of packets, and always allow at least one packet.
+    pub fn new(per_second: f64, burst: Option<f64>) -> Self {
+        Self {
+            per_second,
+            burst: burst.unwrap_or(per_second).max(1.0),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    /// refilled with every packet, so this is also when the source was last seen
+    last_refill: Instant,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => Self::prefix_matches(
+                u32::from(network) as u128,
+                u32::from(addr) as u128,
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(addr)) => Self::prefix_matches(
+                u128::from(network),
+                u128::from(addr),
+                128,
+                self.prefix_len,
+            ),
+            _ => false,
+        }
+    }
+
+    fn prefix_matches(network: u128, addr: u128, bits: u8, prefix: u8) -> bool {
+        let host_bits = bits - prefix;
+        network.checked_shr(host_bits as u32).unwrap_or(0)
+            == addr.checked_shr(host_bits as u32).unwrap_or(0)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix_len) = match s.split_once('/') {
+            Some((network, prefix_len)) => (network, Some(prefix_len)),
+            None => (s, None),
+        };
+        let network = network
+            .parse::<IpAddr>()
+            .map_err(|e| format!("invalid address '{network}': {e}"))?
+            .to_canonical();
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            None => max_prefix_len,
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_prefix_len)
+                .ok_or_else(|| {
+                    format!("invalid prefix length '{prefix_len}'")
+                })?,
+        };
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.last_refill = now;
+    }
+
+    /// Whether the bucket would be full, so forgetting it makes no difference.
+    fn is_full(&self, limit: &RateLimit, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens + elapsed * limit.per_second >= limit.burst
+    }
+
+    fn try_take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+impl SourceFilter {
+    pub fn new(
+        allow: Vec<Cidr>,
+        deny: Vec<Cidr>,
+        rate_limit: Option<RateLimit>,
+    ) -> Self {
+        Self {
+            allow,
+            deny,
+            rate_limit,
+            buckets: HashMap::new(),
+            denied: 0,
+            rate_limited: 0,
+            last_drop_log: Instant::now(),
+        }
+    }
+
+    /// Checks whether a packet from the source should be processed.
+    pub fn accept(&mut self, source: IpAddr) -> Result<(), DropReason> {
+        self.accept_at(source, Instant::now())
+    }
+
+    fn accept_at(
+        &mut self,
+        source: IpAddr,
+        now: Instant,
+    ) -> Result<(), DropReason> {
+        let source = source.to_canonical();
+        let accepted = if !self.is_allowed(source) {
+            self.denied += 1;
+            Err(DropReason::Denied)
+        } else if !self.take_token(source, now) {
+            self.rate_limited += 1;
+            Err(DropReason::RateLimited)
+        } else {
+            Ok(())
+        };
+        self.log_drops();
+        accepted
+    }
+
+    fn is_allowed(&self, source: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(source)) {
+            return false;
+        }
+        self.allow.is_empty()
+            || self.allow.iter().any(|cidr| cidr.contains(source))
+    }
+
+    fn take_token(&mut self, source: IpAddr, now: Instant) -> bool {
+        let Some(limit) = self.rate_limit else {
+            return true;
+        };
+
+        if self.buckets.len() >= MAX_TRACKED_SOURCES
+            && !self.buckets.contains_key(&source)
+        {
+            self.forget_sources(&limit, now);
+        }
+
+        self.buckets
+            .entry(source)
+            .or_insert_with(|| TokenBucket::new(&limit, now))
+            .try_take(&limit, now)
+    }
+
+    /// Makes room for more sources, so a flood from many addresses cannot fill the memory.
+    fn forget_sources(&mut self, limit: &RateLimit, now: Instant) {
+        self.buckets.retain(|_, bucket| !bucket.is_full(limit, now));
+        if self.buckets.len() < MAX_TRACKED_SOURCES {
+            return;
+        }
+        // the buckets of the sources seen the longest time ago are the most likely to have
+        // refilled when they send again, a quarter of them at once so a flood of new sources
+        // does not search for the oldest with every packet
+        let forget = MAX_TRACKED_SOURCES / 4;
+        let mut last_seen = self
+            .buckets
+            .values()
+            .map(|bucket| bucket.last_refill)
+            .collect::<Vec<_>>();
+        let (_, &mut newest_forgotten, _) =
+            last_seen.select_nth_unstable(forget - 1);
+        self.buckets
+            .retain(|_, bucket| bucket.last_refill > newest_forgotten);
+    }
+
+    fn log_drops(&mut self) {
+        if self.denied + self.rate_limited == 0
+            || self.last_drop_log.elapsed() < DROP_LOG_INTERVAL
+        {
+            return;
+        }
+        warn!(
+            "dropped {} packets in the last {}s ({} denied, {} rate limited)",
+            self.denied + self.rate_limited,
+            self.last_drop_log.elapsed().as_secs(),
+            self.denied,
+            self.rate_limited
+        );
+        self.denied = 0;
+        self.rate_limited = 0;
+        self.last_drop_log = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    /// Addresses in 11.0.0.0/8, for tests that need many of them.
+    fn numbered(index: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0x0b00_0000 + index))
+    }
+
+    #[test]
+    fn cidr_prefix_lengths() {
+        let everything = cidr("0.0.0.0/0");
+        assert!(everything.contains(addr("0.0.0.0")));
+        assert!(everything.contains(addr("255.255.255.255")));
+        assert!(!everything.contains(addr("::1")));
+
+        let single = cidr("10.1.2.3/32");
+        assert_eq!(single, cidr("10.1.2.3"));
+        assert!(single.contains(addr("10.1.2.3")));
+        assert!(!single.contains(addr("10.1.2.2")));
+        assert!(!single.contains(addr("10.1.2.4")));
+
+        let subnet = cidr("10.1.0.0/16");
+        assert!(subnet.contains(addr("10.1.255.255")));
+        assert!(!subnet.contains(addr("10.2.0.0")));
+
+        let everything = cidr("::/0");
+        assert!(everything.contains(addr("::")));
+        assert!(everything
+            .contains(addr("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!everything.contains(addr("127.0.0.1")));
+
+        let single = cidr("2001:db8::1/128");
+        assert_eq!(single, cidr("2001:db8::1"));
+        assert!(single.contains(addr("2001:db8::1")));
+        assert!(!single.contains(addr("2001:db8::")));
+        assert!(!single.contains(addr("2001:db8::2")));
+
+        let link_local = cidr("fe80::/10");
+        assert!(link_local.contains(addr("febf::1")));
+        assert!(!link_local.contains(addr("fec0::1")));
+    }
+
+    #[test]
+    fn cidr_ipv4_mapped_addresses() {
+        // dual-stack sockets receive IPv4 packets from mapped addresses
+        assert!(cidr("192.168.0.0/16").contains(addr("::ffff:192.168.1.2")));
+        assert!(!cidr("192.168.0.0/16").contains(addr("::ffff:10.0.0.1")));
+        assert!(cidr("::ffff:10.1.2.3").contains(addr("10.1.2.3")));
+        assert!(!cidr("::/0").contains(addr("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn cidr_parse_errors() {
+        for invalid in [
+            "",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "::ffff:10.0.0.0/9999",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let mut filter = SourceFilter::new(
+            vec![cidr("10.0.0.0/8")],
+            vec![cidr("10.0.0.13")],
+            None,
+        );
+        assert_eq!(filter.accept(addr("10.0.0.12")), Ok(()));
+        assert_eq!(filter.accept(addr("10.0.0.13")), Err(DropReason::Denied));
+        assert_eq!(
+            filter.accept(addr("::ffff:10.0.0.13")),
+            Err(DropReason::Denied)
+        );
+        assert_eq!(filter.accept(addr("192.168.0.1")), Err(DropReason::Denied));
+    }
+
+    #[test]
+    fn deny_without_allow() {
+        let mut filter =
+            SourceFilter::new(Vec::new(), vec![cidr("fe80::/10")], None);
+        assert_eq!(filter.accept(addr("192.168.0.1")), Ok(()));
+        assert_eq!(filter.accept(addr("2001:db8::1")), Ok(()));
+        assert_eq!(filter.accept(addr("fe80::1")), Err(DropReason::Denied));
+    }
+
+    #[test]
+    fn rate_limit_burst() {
+        assert_eq!(RateLimit::new(10.0, None).burst, 10.0);
+        assert_eq!(RateLimit::new(10.0, Some(3.0)).burst, 3.0);
+        // a bucket that can never hold a whole token would drop every packet
+        assert_eq!(RateLimit::new(0.5, None).burst, 1.0);
+        assert_eq!(RateLimit::new(10.0, Some(0.0)).burst, 1.0);
+    }
+
+    #[test]
+    fn token_bucket_burst_and_refill() {
+        let limit = RateLimit::new(2.0, Some(3.0));
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&limit, start);
+        for _ in 0..3 {
+            assert!(bucket.try_take(&limit, start));
+        }
+        assert!(!bucket.try_take(&limit, start));
+
+        // half a second refills one token
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take(&limit, later));
+        assert!(!bucket.try_take(&limit, later));
+
+        // refilling stops at the burst size
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_take(&limit, much_later));
+        }
+        assert!(!bucket.try_take(&limit, much_later));
+    }
+
+    #[test]
+    fn rate_limit_per_source() {
+        let mut filter = SourceFilter::new(
+            Vec::new(),
+            Vec::new(),
+            Some(RateLimit::new(0.001, Some(2.0))),
+        );
+        assert_eq!(filter.accept(addr("10.0.0.1")), Ok(()));
+        assert_eq!(filter.accept(addr("10.0.0.1")), Ok(()));
+        assert_eq!(
+            filter.accept(addr("10.0.0.1")),
+            Err(DropReason::RateLimited)
+        );
+        // mapped addresses share the bucket of the IPv4 address
+        assert_eq!(
+            filter.accept(addr("::ffff:10.0.0.1")),
+            Err(DropReason::RateLimited)
+        );
+        assert_eq!(filter.accept(addr("10.0.0.2")), Ok(()));
+    }
+
+    #[test]
+    fn rate_limit_tracks_bounded_number_of_sources() {
+        let mut filter = SourceFilter::new(
+            Vec::new(),
+            Vec::new(),
+            Some(RateLimit::new(0.001, Some(2.0))),
+        );
+        let start = Instant::now();
+        let first = addr("10.0.0.1");
+        assert_eq!(filter.accept_at(first, start), Ok(()));
+        assert_eq!(filter.accept_at(first, start), Ok(()));
+
+        // none of the buckets refill in time, so all of them would be kept without a bound
+        let flood_start = start + Duration::from_secs(1);
+        for index in 0..2 * MAX_TRACKED_SOURCES as u32 {
+            let source = numbered(index);
+            let now = flood_start + Duration::from_millis(index as u64);
+            assert_eq!(filter.accept_at(source, now), Ok(()));
+            assert!(filter.buckets.len() <= MAX_TRACKED_SOURCES);
+        }
+
+        // the sources seen the longest time ago were forgotten, recent ones are still limited
+        assert!(!filter.buckets.contains_key(&first));
+        assert!(!filter.buckets.contains_key(&numbered(0)));
+        let newest = numbered(2 * MAX_TRACKED_SOURCES as u32 - 1);
+        let end = flood_start + Duration::from_secs(60);
+        assert_eq!(filter.accept_at(newest, end), Ok(()));
+        assert_eq!(filter.accept_at(newest, end), Err(DropReason::RateLimited));
+    }
+
+    #[test]
+    fn rate_limit_forgets_full_buckets_first() {
+        let mut filter = SourceFilter::new(
+            Vec::new(),
+            Vec::new(),
+            Some(RateLimit::new(1.0, Some(1.0))),
+        );
+        let start = Instant::now();
+        let first = addr("10.0.0.1");
+        assert_eq!(filter.accept_at(first, start), Ok(()));
+        for index in 1..MAX_TRACKED_SOURCES as u32 {
+            let later = start + Duration::from_secs(1);
+            filter.accept_at(numbered(index), later).unwrap();
+        }
+
+        // the first bucket refilled, the others not yet
+        let now = start + Duration::from_millis(1500);
+        filter.accept_at(addr("10.0.0.2"), now).unwrap();
+
+        assert_eq!(filter.buckets.len(), MAX_TRACKED_SOURCES);
+        assert!(!filter.buckets.contains_key(&first));
+    }
+}
@@ -1,102 +1,703 @@
-use crate::command_executor::CommandExecute;
 use crate::{
-    command_executor::{CommandExecutionContext, ExecutionResult},
+    cli::MulticastOptions,
+    command_kind::{compression_of, CommandKind},
+    conformance,
+    diagnostics::{self, Problem},
+    firmware::Firmware,
+    front_buffer::FrontBuffer,
     gui::AppEvents,
+    local_input::LocalInput,
+    metrics::METRICS,
+    packet_log::PacketEvent,
+    packet_pipeline::PacketPipeline,
+    sender_displays::{ClientDisplays, SenderDisplay},
 };
-use log::{debug, error, warn};
-use servicepoint::TypedCommand;
+use log::{debug, error, info, warn};
+use polling::{Event, Events, Poller};
+use servicepoint::{Grid, TryFromPacketError, TypedCommand};
+use servicepoint_simulator::{ExecutionResult, Simulator};
 use std::{
-    io::ErrorKind, net::UdpSocket, sync::mpsc::Receiver, time::Duration,
+    fmt::Debug,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::{
+        mpsc::{self, Receiver, Sender, SyncSender},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 use winit::event_loop::EventLoopProxy;
 
+/// Receives the events of the [UdpServer], e.g. the GUI event loop.
+pub trait AppEventSink: Send + Debug {
+    fn send_app_event(&self, event: AppEvents);
+}
+
 const BUF_SIZE: usize = 8985 * 2;
+/// How many packets from other threads wait to be received before [PacketQueue::push] blocks.
+const QUEUE_SIZE: usize = 64;
+/// How long packets are executed in a row before the GUI is told to redraw,
+/// so a sender that never pauses still gets to see its changes.
+const MAX_BATCH_DURATION: Duration = Duration::from_millis(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum SocketError {
+    #[error("could not bind to {0}: {1}")]
+    Bind(String, std::io::Error),
+    #[cfg(unix)]
+    #[error("could not bind to {path}: {1}", path = .0.display())]
+    BindUnix(std::path::PathBuf, std::io::Error),
+    #[error("could not join multicast group {0}: {1}")]
+    JoinMulticast(IpAddr, std::io::Error),
+    #[error("no socket is bound to an address of the same family as multicast group {0}")]
+    NoSocketForGroup(IpAddr),
+    #[error("could not wait for packets: {0}")]
+    Poll(std::io::Error),
+}
+
+/// Binds a non-blocking socket for every address.
+pub fn bind_sockets(
+    addresses: &[String],
+) -> Result<Vec<UdpSocket>, SocketError> {
+    addresses
+        .iter()
+        .map(|address| {
+            UdpSocket::bind(address)
+                .and_then(|socket| {
+                    socket.set_nonblocking(true)?;
+                    Ok(socket)
+                })
+                .map_err(|err| SocketError::Bind(address.clone(), err))
+        })
+        .collect()
+}
+
+/// Joins every group on all sockets bound to an address of the same family.
+///
+/// The groups are left when the sockets are closed.
+pub fn join_multicast(
+    sockets: &[UdpSocket],
+    options: &MulticastOptions,
+) -> Result<(), SocketError> {
+    for &group in &options.groups {
+        let mut joined = false;
+        for socket in sockets {
+            let local = socket
+                .local_addr()
+                .map_err(|err| SocketError::JoinMulticast(group, err))?;
+            let result = match (group, local.ip()) {
+                (IpAddr::V4(group), IpAddr::V4(_)) => socket
+                    .join_multicast_v4(&group, &options.multicast_interface),
+                (IpAddr::V6(group), IpAddr::V6(_)) => socket.join_multicast_v6(
+                    &group,
+                    options.multicast_interface_index,
+                ),
+                _ => continue,
+            };
+            result.map_err(|err| SocketError::JoinMulticast(group, err))?;
+            info!("joined multicast group {group} on {local}");
+            joined = true;
+        }
+        if !joined {
+            return Err(SocketError::NoSocketForGroup(group));
+        }
+    }
+    Ok(())
+}
 
 #[derive(Debug)]
 pub struct UdpServer<'t> {
-    socket: UdpSocket,
+    sockets: Vec<UdpSocket>,
+    local: LocalInput,
+    /// the socket to receive from first, so a busy socket cannot starve the others,
+    /// the local input comes after all UDP sockets
+    next_socket: usize,
+    poller: Arc<Poller>,
+    events: Events,
+    stop_tx: Sender<()>,
     stop_rx: Receiver<()>,
-    command_executor: CommandExecutionContext<'t>,
-    app_events: EventLoopProxy<AppEvents>,
+    queue_tx: SyncSender<(Vec<u8>, SocketAddr)>,
+    queue_rx: Receiver<(Vec<u8>, SocketAddr)>,
+    /// executes the commands on the shared display
+    simulator: &'t Simulator,
+    front: &'t FrontBuffer,
+    clients: ClientDisplays<'t>,
+    /// virtual displays of senders that changed since they were last presented
+    changed_senders: Vec<Arc<SenderDisplay>>,
+    pipeline: PacketPipeline,
+    app_events: Box<dyn AppEventSink + 't>,
+    firmware: Firmware,
+    last_packet: Instant,
+    /// packets executed since the GUI was last told to redraw
+    batch: usize,
+    /// when the display first changed since the GUI was last told to redraw
+    batch_start: Option<Instant>,
+    /// senders that were already told that they use a deprecated command
     buf: [u8; BUF_SIZE],
 }
 
 impl<'t> UdpServer<'t> {
+    /// Creates a server receiving on all sockets, which have to be non-blocking.
     pub fn new(
-        bind: String,
-        stop_rx: Receiver<()>,
-        command_executor: CommandExecutionContext<'t>,
-        app_events: EventLoopProxy<AppEvents>,
-    ) -> Self {
-        let socket = UdpSocket::bind(bind).expect("could not bind socket");
-        socket
-            .set_nonblocking(true)
-            .expect("could not enter non blocking mode");
-
-        Self {
-            socket,
+        sockets: Vec<UdpSocket>,
+        simulator: &'t Simulator,
+        front: &'t FrontBuffer,
+        clients: ClientDisplays<'t>,
+        pipeline: PacketPipeline,
+        app_events: Box<dyn AppEventSink + 't>,
+        firmware: Firmware,
+    ) -> Result<Self, SocketError> {
+        assert!(!sockets.is_empty(), "no socket to receive packets on");
+        let poller = Poller::new().map_err(SocketError::Poll)?;
+        for (key, socket) in sockets.iter().enumerate() {
+            // SAFETY: the sockets are removed from the poller before they are closed, see Drop
+            unsafe { poller.add(socket, Event::readable(key)) }
+                .map_err(SocketError::Poll)?;
+        }
+        let (stop_tx, stop_rx) = mpsc::channel();
+        // a bounded queue makes fast senders wait instead of dropping packets
+        let (queue_tx, queue_rx) = mpsc::sync_channel(QUEUE_SIZE);
+        Ok(Self {
+            sockets,
+            local: LocalInput::default(),
+            next_socket: 0,
+            poller: Arc::new(poller),
+            events: Events::new(),
+            stop_tx,
             stop_rx,
-            command_executor,
+            queue_tx,
+            queue_rx,
+            simulator,
+            front,
+            clients,
+            changed_senders: Vec::new(),
+            pipeline,
             app_events,
+            firmware,
+            last_packet: Instant::now(),
+            batch: 0,
+            batch_start: None,
             buf: [0; BUF_SIZE],
+        })
+    }
+
+    /// Also receives packets from the Unix socket and stdin of the local input.
+    pub fn add_local_input(
+        &mut self,
+        local: LocalInput,
+    ) -> Result<(), SocketError> {
+        #[cfg(unix)]
+        if let Some(socket) = local.unix_socket() {
+            // SAFETY: the socket is removed from the poller before it is closed, see Drop
+            unsafe {
+                self.poller.add(socket, Event::readable(self.sockets.len()))
+            }
+            .map_err(SocketError::Poll)?;
         }
+        local.read_stdin(self.packet_queue());
+        self.local = local;
+        Ok(())
+    }
+
+    /// Returns a handle to stop [Self::run] from another thread.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle {
+            stop_tx: self.stop_tx.clone(),
+            poller: Arc::clone(&self.poller),
+        }
+    }
+
+    /// Returns a handle to pass packets received by other threads to [Self::run].
+    pub fn packet_queue(&self) -> PacketQueue {
+        PacketQueue {
+            queue_tx: self.queue_tx.clone(),
+            poller: Arc::clone(&self.poller),
+        }
+    }
+
+    /// The address of the first socket, e.g. for the boot splash.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.sockets[0]
+            .local_addr()
+            .expect("could not get address of bound socket")
     }
 
     pub(crate) fn run(&mut self) {
+        if self.firmware.boot_splash {
+            self.firmware.boot(self.simulator, self.local_addr());
+            self.present();
+            self.display_changed(0);
+        }
+
         while self.stop_rx.try_recv().is_err() {
-            if let Some(cmd) = self.receive_into_buf().and_then(|amount| {
-                Self::command_from_slice(&self.buf[..amount])
-            }) {
-                debug!("received {cmd:?}");
-                match cmd.execute(&self.command_executor) {
-                    ExecutionResult::Success => {
-                        self.app_events
-                            .send_event(AppEvents::UdpPacketHandled)
-                            .expect("could not send packet handled event");
-                    }
-                    ExecutionResult::Failure => {
-                        error!("failed to execute command");
-                    }
-                    ExecutionResult::Shutdown => {
-                        self.app_events
-                            .send_event(AppEvents::UdpThreadClosed)
-                            .expect("could not send close event");
-                        break;
+            let received = self.receive_into_buf();
+            if let Some((amount, source)) = received {
+                self.pipeline.push(&self.buf[..amount], source);
+            }
+
+            if let Some(screensaver) = &mut self.firmware.screensaver {
+                if screensaver.tick(self.simulator, self.last_packet) {
+                    self.present_screensaver();
+                    self.display_changed(0);
+                }
+            }
+
+            let Some((packet, source)) = self.pipeline.pop() else {
+                // nothing left to execute right now, so show everything executed so far
+                self.flush_batch();
+                if received.is_none() {
+                    self.wait();
+                }
+                continue;
+            };
+            let mut event = PacketEvent::new(source, &packet);
+            let Some(cmd) =
+                Self::command_from_slice(&packet, source, &mut event)
+            else {
+                event.log();
+                continue;
+            };
+            if self.firmware.strict
+                && !Self::conforms(&packet, &cmd, source, &mut event)
+            {
+                event.log();
+                continue;
+            }
+            let kind = CommandKind::from(&cmd);
+            self.pipeline.charge(kind);
+            self.last_packet = Instant::now();
+            if let Some(screensaver) = &mut self.firmware.screensaver {
+                if screensaver.wake(self.simulator) {
+                    self.wake_senders();
+                }
+            }
+
+            debug!("received {cmd:?} from {source}");
+            let result = self.execute(cmd, source);
+            event.executed(result);
+            event.log();
+            match result {
+                ExecutionResult::Success => {
+                    if self.firmware.frame_marker == Some(kind) {
+                        self.present_frame(source);
                     }
+                    self.display_changed(1);
+                }
+                ExecutionResult::Failure => {
+                    METRICS.execution_failed();
+                    error!("failed to execute command");
+                    diagnostics::explain(source, &packet, Problem::Execution);
                 }
+                ExecutionResult::Shutdown => {
+                    self.flush_batch();
+                    self.app_events.send_app_event(AppEvents::UdpThreadClosed);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Adds executed packets to the current batch, which is shown when it gets too old.
+    fn display_changed(&mut self, packets: usize) {
+        self.batch += packets;
+        let batch_start = *self.batch_start.get_or_insert_with(Instant::now);
+        if batch_start.elapsed() >= MAX_BATCH_DURATION {
+            self.flush_batch();
+        }
+    }
+
+    /// Tells the GUI to redraw once for all changes since the last time.
+    fn flush_batch(&mut self) {
+        if self.batch_start.take().is_some() {
+            if self.firmware.frame_marker.is_none() {
+                self.present();
+            }
+            self.app_events
+                .send_app_event(AppEvents::UdpPacketsHandled(self.batch));
+            self.batch = 0;
+        }
+    }
+
+    /// Shows the current state of all displays, see [FrontBuffer].
+    fn present(&mut self) {
+        self.composite();
+        self.front.present(self.simulator.snapshot());
+        for sender in self.changed_senders.drain(..) {
+            sender.front.present(sender.simulator.snapshot());
+        }
+    }
+
+    /// Shows the frame a sender completed by sending the frame marker.
+    fn present_frame(&mut self, source: SocketAddr) {
+        self.composite();
+        self.front.present(self.simulator.snapshot());
+        let Some(senders) = self.clients.senders() else {
+            return;
+        };
+        if let Some(sender) = senders.get(source.ip()) {
+            sender.front.present(sender.simulator.snapshot());
+        }
+    }
+
+    /// Shows the screensaver, which runs on the shared display, on every display.
+    ///
+    /// The virtual displays of the senders keep their content, only their shown state is replaced.
+    /// Nothing is composited, as that would draw over the screensaver.
+    fn present_screensaver(&mut self) {
+        let frame = self.simulator.snapshot();
+        if let Some(senders) = self.clients.senders() {
+            for sender in senders.all() {
+                sender.front.present(frame.clone());
+            }
+        }
+        self.front.present(frame);
+    }
+
+    /// Shows the content of all virtual displays again after the screensaver stopped.
+    fn wake_senders(&mut self) {
+        // with frame markers, the screensaver is shown until the next frame like on the shared display
+        if self.firmware.frame_marker.is_some() {
+            return;
+        }
+        if let Some(senders) = self.clients.senders() {
+            self.changed_senders = senders.all();
+        }
+    }
+
+    fn composite(&self) {
+        if let ClientDisplays::Composited(compositor) = &self.clients {
+            compositor.composite();
+        }
+    }
+
+    fn execute(
+        &mut self,
+        cmd: TypedCommand,
+        source: SocketAddr,
+    ) -> ExecutionResult {
+        let Some(senders) = self.clients.senders() else {
+            return self.execute_on(cmd, self.simulator);
+        };
+
+        let sender = senders.get_or_insert(source, self.simulator);
+        let result = self.execute_on(cmd, &sender.simulator);
+        if let ClientDisplays::Composited(compositor) = &self.clients {
+            compositor.sender_changed();
+        }
+        if self.firmware.frame_marker.is_none()
+            && !self
+                .changed_senders
+                .iter()
+                .any(|changed| Arc::ptr_eq(changed, &sender))
+        {
+            self.changed_senders.push(sender);
+        }
+        result
+    }
+
+    fn execute_on(
+        &self,
+        cmd: TypedCommand,
+        simulator: &Simulator,
+    ) -> ExecutionResult {
+        let kind = CommandKind::from(&cmd);
+        let start = Instant::now();
+        let result = simulator.execute(cmd);
+        METRICS.executed(kind, start.elapsed());
+        match result {
+            ExecutionResult::Shutdown => {
+                self.firmware.hard_reset(simulator, self.local_addr())
             }
+            result => result,
         }
     }
 
-    fn command_from_slice(slice: &[u8]) -> Option<TypedCommand> {
+    fn command_from_slice(
+        slice: &[u8],
+        source: SocketAddr,
+        event: &mut PacketEvent,
+    ) -> Option<TypedCommand> {
         let packet = servicepoint::Packet::try_from(slice)
-            .inspect_err(|_| {
-                warn!("could not load packet with length {}", slice.len())
+            .inspect_err(|err| {
+                METRICS.too_short();
+                event.failed(err.to_string());
+                warn!("could not load packet with length {}", slice.len());
+                diagnostics::explain(source, slice, Problem::TooShort);
             })
             .ok()?;
+        let compression = compression_of(&packet.header);
+        let compressed_size = packet.payload.as_ref().map_or(0, Vec::len);
+        let start = Instant::now();
         TypedCommand::try_from(packet)
-            .inspect_err(move |err| {
-                warn!("could not read command for packet: {:?}", err)
+            .inspect(|cmd| {
+                METRICS.decoded(CommandKind::from(cmd));
+                event.decoded(CommandKind::from(cmd));
+                if let Some(compression) = compression {
+                    METRICS.decompressed(
+                        source.ip(),
+                        compression,
+                        compressed_size,
+                        decompressed_size(cmd),
+                        start.elapsed(),
+                    );
+                }
+            })
+            .inspect_err(|err| {
+                METRICS.decode_failed(err);
+                if let (
+                    TryFromPacketError::DecompressionFailed,
+                    Some(compression),
+                ) = (err, compression)
+                {
+                    METRICS.decompression_failed(source.ip(), compression);
+                }
+                event.failed(format!("{err:?}"));
+                warn!("could not read command for packet: {:?}", err);
+                diagnostics::explain(source, slice, Problem::Decode(err));
             })
             .ok()
     }
 
-    fn receive_into_buf(&mut self) -> Option<usize> {
-        let (amount, _) = match self.socket.recv_from(&mut self.buf) {
-            Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                std::thread::sleep(Duration::from_millis(1));
-                return None;
-            }
-            Ok(result) => result,
-            other => other.unwrap(),
-        };
+    fn conforms(
+        packet: &[u8],
+        cmd: &TypedCommand,
+        source: SocketAddr,
+        event: &mut PacketEvent,
+    ) -> bool {
+        let may_be_truncated = packet.len() >= BUF_SIZE;
+        let deviations = conformance::deviations(packet, cmd, may_be_truncated);
+        if deviations.is_empty() {
+            return true;
+        }
+        METRICS.nonconforming();
+        let message = deviations
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        warn!("rejecting packet from {source} in strict mode: {message}");
+        event.failed(message);
+        diagnostics::explain(
+            source,
+            packet,
+            Problem::Nonconforming(&deviations),
+        );
+        false
+    }
+
+    fn receive_into_buf(&mut self) -> Option<(usize, SocketAddr)> {
+        let (amount, source) = self.receive_from_any()?;
+        // IPv4 senders on a dual-stack IPv6 socket show up as mapped addresses
+        let source = SocketAddr::new(source.ip().to_canonical(), source.port());
 
-        if amount == self.buf.len() {
+        let truncated = amount == self.buf.len();
+        METRICS.received(source.ip(), amount, truncated);
+        if truncated {
             warn!(
                 "the received package may have been truncated to a length of {}",
                 amount
             );
         }
-        Some(amount)
+        Some((amount, source))
+    }
+
+    /// Blocks until a socket is readable, something is due or the server is stopped.
+    fn wait(&mut self) {
+        let screensaver =
+            self.firmware.screensaver.as_ref().and_then(|screensaver| {
+                screensaver.next_tick(self.last_packet)
+            });
+        let timeout = self
+            .pipeline
+            .next_ready()
+            .into_iter()
+            .chain(screensaver)
+            .min()
+            .map(|due| due.saturating_duration_since(Instant::now()));
+
+        for (key, socket) in self.sockets.iter().enumerate() {
+            // events only fire once, so the sockets have to be armed again every time
+            if let Err(err) = self.poller.modify(socket, Event::readable(key)) {
+                error!("could not wait for packets: {err}");
+            }
+        }
+        #[cfg(unix)]
+        if let Some(socket) = self.local.unix_socket() {
+            let event = Event::readable(self.sockets.len());
+            if let Err(err) = self.poller.modify(socket, event) {
+                error!("could not wait for packets: {err}");
+            }
+        }
+        self.events.clear();
+        if let Err(err) = self.poller.wait(&mut self.events, timeout) {
+            if err.kind() != ErrorKind::Interrupted {
+                error!("could not wait for packets: {err}");
+            }
+        }
+    }
+
+    fn receive_from_any(&mut self) -> Option<(usize, SocketAddr)> {
+        let inputs = self.sockets.len() + 1;
+        for _ in 0..inputs {
+            let index = self.next_socket;
+            self.next_socket = (self.next_socket + 1) % inputs;
+            let Some(socket) = self.sockets.get(index) else {
+                let received = self
+                    .local
+                    .receive(&mut self.buf)
+                    .or_else(|| self.receive_queued());
+                if received.is_some() {
+                    return received;
+                }
+                continue;
+            };
+            match socket.recv_from(&mut self.buf) {
+                Ok(received) => return Some(received),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                // e.g. an ICMP error for a previous packet, the socket is still usable
+                Err(err) => warn!("could not receive packet: {err}"),
+            }
+        }
+        None
+    }
+
+    /// Copies a packet from the [PacketQueue], truncating it to the receive buffer.
+    fn receive_queued(&mut self) -> Option<(usize, SocketAddr)> {
+        let (packet, source) = self.queue_rx.try_recv().ok()?;
+        let amount = packet.len().min(self.buf.len());
+        self.buf[..amount].copy_from_slice(&packet[..amount]);
+        Some((amount, source))
+    }
+}
+
+impl Drop for UdpServer<'_> {
+    fn drop(&mut self) {
+        for socket in &self.sockets {
+            let _ = self.poller.delete(socket);
+        }
+        #[cfg(unix)]
+        if let Some(socket) = self.local.unix_socket() {
+            let _ = self.poller.delete(socket);
+        }
+    }
+}
+
+/// Stops a running [UdpServer], e.g. when the window is closed.
+#[derive(Debug, Clone)]
+pub struct StopHandle {
+    stop_tx: Sender<()>,
+    poller: Arc<Poller>,
+}
+
+impl StopHandle {
+    pub fn stop(&self) {
+        // the server may have stopped already
+        let _ = self.stop_tx.send(());
+        let _ = self.poller.notify();
+    }
+}
+
+/// Passes packets received by other threads to a [UdpServer], e.g. from stdin.
+#[derive(Debug, Clone)]
+pub struct PacketQueue {
+    queue_tx: SyncSender<(Vec<u8>, SocketAddr)>,
+    poller: Arc<Poller>,
+}
+
+impl PacketQueue {
+    /// Waits until the packet fits into the queue.
+    ///
+    /// Returns `false` if the server stopped and will not receive packets anymore.
+    pub fn push(&self, packet: Vec<u8>, source: SocketAddr) -> bool {
+        if self.queue_tx.send((packet, source)).is_err() {
+            return false;
+        }
+        let _ = self.poller.notify();
+        true
+    }
+}
+
+/// The size of the pixel data of a command after decompression.
+fn decompressed_size(cmd: &TypedCommand) -> usize {
+    match cmd {
+        TypedCommand::Bitmap(command) => {
+            command.bitmap.width() / 8 * command.bitmap.height()
+        }
+        TypedCommand::BitVec(command) => command.bitvec.len() / 8,
+        _ => 0,
+    }
+}
+
+impl AppEventSink for EventLoopProxy<AppEvents> {
+    fn send_app_event(&self, event: AppEvents) {
+        self.send_event(event).expect("could not send app event");
+    }
+}
+
+impl AppEventSink for Sender<AppEvents> {
+    fn send_app_event(&self, event: AppEvents) {
+        // nobody is waiting for events anymore if the receiver is gone
+        let _ = self.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn multicast(groups: Vec<IpAddr>) -> MulticastOptions {
+        MulticastOptions {
+            groups,
+            multicast_interface: Ipv4Addr::LOCALHOST,
+            multicast_interface_index: 0,
+        }
+    }
+
+    #[test]
+    fn receives_packets_sent_to_joined_group() {
+        let group = Ipv4Addr::new(239, 255, 42, 1);
+        let sockets = bind_sockets(&[String::from("0.0.0.0:0")]).unwrap();
+        join_multicast(&sockets, &multicast(vec![group.into()])).unwrap();
+        let socket = &sockets[0];
+        socket.set_nonblocking(false).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        sender.send_to(b"hello", (group, port)).unwrap();
+        let mut buf = [0; 16];
+        let (amount, source) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..amount], b"hello");
+        assert_eq!(source, sender.local_addr().unwrap());
+
+        // without the membership, packets sent to the group do not arrive
+        socket
+            .leave_multicast_v4(&group, &Ipv4Addr::LOCALHOST)
+            .unwrap();
+        socket.set_nonblocking(true).unwrap();
+        sender.send_to(b"hello", (group, port)).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            socket.recv_from(&mut buf).unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn joins_only_on_sockets_of_same_family() {
+        let group = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x4242, 1);
+        let sockets = bind_sockets(&[String::from("127.0.0.1:0")]).unwrap();
+        assert!(matches!(
+            join_multicast(&sockets, &multicast(vec![group.into()])),
+            Err(SocketError::NoSocketForGroup(failed)) if failed == group
+        ));
+
+        let group = Ipv4Addr::new(239, 255, 42, 2);
+        assert!(
+            join_multicast(&sockets, &multicast(vec![group.into()])).is_ok()
+        );
     }
 }
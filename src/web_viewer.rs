@@ -0,0 +1,573 @@
+use crate::{
+    front_buffer::FrontBuffer,
+    udp_server::PacketQueue,
+    websocket::{self, Message, OPCODE_BINARY, OPCODE_CLOSE, OPCODE_PONG},
+};
+use log::{debug, info, warn};
+use servicepoint::DataRef;
+use servicepoint_simulator::snapshot::Snapshot;
+use std::{
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+const PAGE: &str = include_str!("web_viewer.html");
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Browsers send far smaller requests, bigger ones are turned away before they fill the memory.
+const MAX_REQUEST_SIZE: u64 = 8192;
+/// Limits the frames sent to each browser to 30 per second.
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+/// How often a connection checks whether the browser went away while the display does not change.
+const CLOSED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Packets have to fit into a UDP datagram, so bigger messages are not packets.
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+/// Every connection has its own threads, so further connections are turned away.
+const MAX_CONNECTIONS: usize = 32;
+
+/// Serves a page showing the front buffer in the browser, updated over a WebSocket.
+///
+/// Binary messages sent over the WebSocket are executed as packets if `input` is set.
+/// Pages on other sites may then only connect if their origin is in `origins`.
+pub fn serve(
+    bind: &str,
+    front: Arc<FrontBuffer>,
+    input: Option<PacketQueue>,
+    origins: Vec<String>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(bind)?;
+    info!("serving web viewer on http://{}/", listener.local_addr()?);
+    std::thread::Builder::new()
+        .name(String::from("web viewer"))
+        .spawn(move || {
+            let connections = Arc::new(AtomicUsize::new(0));
+            let origins: Arc<[String]> = origins.into();
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("could not accept web viewer connection: {err}");
+                        continue;
+                    }
+                };
+                let Some(connection) = Connection::open(&connections) else {
+                    warn!("turning away web viewer connection, there are already {MAX_CONNECTIONS}");
+                    let _ = stream.set_write_timeout(Some(REQUEST_TIMEOUT));
+                    let _ = write_response(
+                        &mut stream,
+                        "503 Service Unavailable",
+                        "text/plain",
+                        "too many connections\n",
+                    );
+                    continue;
+                };
+                let front = Arc::clone(&front);
+                let input = input.clone();
+                let origins = Arc::clone(&origins);
+                // browsers stay connected, so every connection needs its own thread
+                std::thread::spawn(move || {
+                    if let Err(err) = respond(stream, &front, input, &origins)
+                    {
+                        debug!("web viewer connection failed: {err}");
+                    }
+                    drop(connection);
+                });
+            }
+        })?;
+    Ok(())
+}
+
+/// Counts an open connection until dropped.
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    /// Returns `None` if there are already [MAX_CONNECTIONS].
+    fn open(connections: &Arc<AtomicUsize>) -> Option<Self> {
+        connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                (open < MAX_CONNECTIONS).then_some(open + 1)
+            })
+            .ok()?;
+        Some(Self(Arc::clone(connections)))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn respond(
+    mut stream: TcpStream,
+    front: &FrontBuffer,
+    input: Option<PacketQueue>,
+    origins: &[String],
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let request = match read_request(&stream) {
+        Ok(request) => request,
+        Err(err) if err.kind() == ErrorKind::InvalidData => {
+            return write_response(
+                &mut stream,
+                "400 Bad Request",
+                "text/plain",
+                &format!("{err}\n"),
+            );
+        }
+        Err(err) => return Err(err),
+    };
+
+    match (request.path.as_str(), &request.websocket_key) {
+        ("/", _) => write_response(
+            &mut stream,
+            "200 OK",
+            "text/html; charset=utf-8",
+            PAGE,
+        ),
+        // any page could otherwise draw on the display through the browser of a visitor
+        ("/ws", Some(_))
+            if input.is_some() && !request.has_allowed_origin(origins) =>
+        {
+            warn!(
+                "refusing WebSocket connection from a page on {}, allow it with --web-origin",
+                request.origin.as_deref().unwrap_or_default()
+            );
+            write_response(
+                &mut stream,
+                "403 Forbidden",
+                "text/plain",
+                "pages on this origin may not send packets\n",
+            )
+        }
+        ("/ws", Some(key)) => {
+            write!(
+                stream,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                websocket::accept_key(key)
+            )?;
+            stream_display(stream, front, input)
+        }
+        ("/ws", None) => write_response(
+            &mut stream,
+            "400 Bad Request",
+            "text/plain",
+            "expected a WebSocket connection\n",
+        ),
+        _ => write_response(
+            &mut stream,
+            "404 Not Found",
+            "text/plain",
+            "not found, try /\n",
+        ),
+    }
+}
+
+/// The parts of an HTTP request the viewer looks at.
+#[derive(Debug, Default, PartialEq)]
+struct Request {
+    path: String,
+    host: Option<String>,
+    origin: Option<String>,
+    websocket_key: Option<String>,
+}
+
+impl Request {
+    /// Whether the request comes from the page of the viewer itself or from a page on one of `origins`.
+    ///
+    /// Requests without an origin do not come from a browser, so no page is involved.
+    fn has_allowed_origin(&self, origins: &[String]) -> bool {
+        let Some(origin) = &self.origin else {
+            return true;
+        };
+        let same_origin = match (origin.split_once("://"), &self.host) {
+            (Some((_, authority)), Some(host)) => {
+                authority.eq_ignore_ascii_case(host)
+            }
+            _ => false,
+        };
+        same_origin
+            || origins.iter().any(|allowed| {
+                allowed.trim_end_matches('/').eq_ignore_ascii_case(origin)
+            })
+    }
+}
+
+/// Reads the request line and the headers, which have to fit into [MAX_REQUEST_SIZE] bytes.
+fn read_request(reader: impl Read) -> io::Result<Request> {
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST_SIZE));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut request = Request {
+        path: line
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or_default()
+            .to_owned(),
+        ..Request::default()
+    };
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            if reader.get_ref().limit() == 0 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("request is larger than {MAX_REQUEST_SIZE} bytes"),
+                ));
+            }
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        if line.trim_end().is_empty() {
+            return Ok(request);
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = Some(value.trim().to_owned());
+        match name.trim().to_ascii_lowercase().as_str() {
+            "host" => request.host = value,
+            "origin" => request.origin = value,
+            "sec-websocket-key" => request.websocket_key = value,
+            _ => {}
+        }
+    }
+}
+
+fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// Sends a frame whenever the display changes, until the browser goes away.
+fn stream_display(
+    stream: TcpStream,
+    front: &FrontBuffer,
+    input: Option<PacketQueue>,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    info!("web viewer {peer} connected");
+    // messages from the browser may take any time, frames are still limited by the write timeout
+    stream.set_read_timeout(None)?;
+    stream.set_nodelay(true)?;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let closed = Arc::new(AtomicBool::new(false));
+    {
+        let writer = Arc::clone(&writer);
+        let closed = Arc::clone(&closed);
+        std::thread::spawn(move || {
+            receive_messages(stream, peer, &writer, input);
+            closed.store(true, Ordering::Relaxed);
+        });
+    }
+
+    let result = send_frames(front, &writer, &closed);
+    let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+    info!("web viewer {peer} disconnected");
+    result
+}
+
+fn send_frames(
+    front: &FrontBuffer,
+    writer: &Mutex<TcpStream>,
+    closed: &AtomicBool,
+) -> io::Result<()> {
+    // read before the state, so a state presented in between is sent again instead of missed
+    let mut presented = front.presented();
+    loop {
+        let frame = encode_frame(&front.read());
+        websocket::write_message(
+            &mut *writer.lock().unwrap(),
+            OPCODE_BINARY,
+            &frame,
+        )?;
+        // changes until the next frame are sent together
+        std::thread::sleep(FRAME_INTERVAL);
+
+        loop {
+            if closed.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let newest =
+                front.wait_for_present(presented, CLOSED_CHECK_INTERVAL);
+            if newest != presented {
+                presented = newest;
+                break;
+            }
+        }
+    }
+}
+
+/// Executes binary messages as packets, answers pings and stops when the browser closes the connection.
+fn receive_messages(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    writer: &Mutex<TcpStream>,
+    input: Option<PacketQueue>,
+) {
+    let mut warned = false;
+    loop {
+        let message =
+            match websocket::read_message(&mut stream, MAX_MESSAGE_SIZE) {
+                Ok(message) => message,
+                Err(err) => {
+                    debug!(
+                        "could not read message from web viewer {peer}: {err}"
+                    );
+                    return;
+                }
+            };
+        match message {
+            Message::Binary(packet) => {
+                if let Some(input) = &input {
+                    if !input.push(packet, peer) {
+                        return;
+                    }
+                } else if !warned {
+                    warned = true;
+                    warn!("ignoring packets from web viewer {peer}, start with --web-input to execute them");
+                }
+            }
+            Message::Text if !warned => {
+                warned = true;
+                warn!("ignoring text message from web viewer {peer}, packets have to be sent as binary messages");
+            }
+            Message::Text | Message::Pong => {}
+            Message::Ping(payload) => {
+                let mut writer = writer.lock().unwrap();
+                if websocket::write_message(&mut *writer, OPCODE_PONG, &payload)
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Message::Close => {
+                let mut writer = writer.lock().unwrap();
+                let _ =
+                    websocket::write_message(&mut *writer, OPCODE_CLOSE, &[]);
+                return;
+            }
+        }
+    }
+}
+
+/// The pixels in the layout of the bitmap commands, followed by one brightness per tile.
+fn encode_frame(state: &Snapshot) -> Vec<u8> {
+    let mut frame = state.bitmap().data_ref().to_vec();
+    frame.extend(state.brightness().data_ref().iter().map(|&b| u8::from(b)));
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use servicepoint::{
+        Bitmap, BrightnessGrid, DataRef, TILE_HEIGHT, TILE_WIDTH,
+    };
+    use std::thread::JoinHandle;
+
+    const UPGRADE: &str = "GET /ws HTTP/1.1\r\nHost: 127.0.0.1:8080\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+
+    fn front_buffer() -> Arc<FrontBuffer> {
+        let mut bitmap = Bitmap::max_sized();
+        bitmap.data_ref_mut()[..3].copy_from_slice(&[1, 2, 3]);
+        let brightness = BrightnessGrid::new(TILE_WIDTH, TILE_HEIGHT);
+        Arc::new(FrontBuffer::new(
+            Snapshot::new(bitmap, brightness).unwrap(),
+            None,
+        ))
+    }
+
+    /// Answers a single connection like [serve] and returns the client side of it.
+    fn connect(
+        front: &Arc<FrontBuffer>,
+    ) -> (TcpStream, JoinHandle<io::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(REQUEST_TIMEOUT)).unwrap();
+        let front = Arc::clone(front);
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            respond(stream, &front, None, &[])
+        });
+        (client, server)
+    }
+
+    fn read_response_head(client: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            client.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// Reads a frame sent by the server, which is never masked.
+    fn read_server_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        client.read_exact(&mut head).unwrap();
+        let length = match head[1] {
+            126 => {
+                let mut length = [0; 2];
+                client.read_exact(&mut length).unwrap();
+                u16::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        client.read_exact(&mut payload).unwrap();
+        (head[0], payload)
+    }
+
+    fn request(text: &str) -> Request {
+        read_request(text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn read_websocket_request() {
+        assert_eq!(
+            request(UPGRADE.replace("Host", "HOST").as_str()),
+            Request {
+                path: String::from("/ws"),
+                host: Some(String::from("127.0.0.1:8080")),
+                origin: None,
+                websocket_key: Some(String::from("dGhlIHNhbXBsZSBub25jZQ==")),
+            }
+        );
+    }
+
+    #[test]
+    fn read_request_ends_at_empty_line() {
+        let request = request(
+            "GET / HTTP/1.1\r\ninvalid\r\n\r\nOrigin: http://a\r\n\r\n",
+        );
+        assert_eq!(request.path, "/");
+        assert_eq!(request.origin, None);
+    }
+
+    #[test]
+    fn read_truncated_request() {
+        let err =
+            read_request(&b"GET / HTTP/1.1\r\nHost: a\r\n"[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn read_request_limits_size() {
+        let huge = format!(
+            "GET / HTTP/1.1\r\nCookie: {}\r\n\r\n",
+            "a".repeat(MAX_REQUEST_SIZE as usize)
+        );
+        let err = read_request(huge.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // an endless line is cut off as well
+        let err = read_request(io::repeat(b'a')).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn allowed_origins() {
+        let origins = [String::from("https://example.com/")];
+        let with_origin = |origin: &str| Request {
+            host: Some(String::from("simulator:8080")),
+            origin: Some(origin.to_owned()),
+            ..Request::default()
+        };
+
+        assert!(Request::default().has_allowed_origin(&[]));
+        assert!(with_origin("http://simulator:8080").has_allowed_origin(&[]));
+        assert!(with_origin("http://SIMULATOR:8080").has_allowed_origin(&[]));
+        assert!(with_origin("https://example.com").has_allowed_origin(&origins));
+
+        assert!(!with_origin("http://simulator").has_allowed_origin(&[]));
+        assert!(
+            !with_origin("http://evil.example").has_allowed_origin(&origins)
+        );
+        assert!(!with_origin("https://example.com").has_allowed_origin(&[]));
+        assert!(!with_origin("null").has_allowed_origin(&origins));
+        let without_host = Request {
+            host: None,
+            ..with_origin("http://simulator:8080")
+        };
+        assert!(!without_host.has_allowed_origin(&[]));
+    }
+
+    #[test]
+    fn websocket_handshake_and_frame() {
+        let front = front_buffer();
+        let (mut client, server) = connect(&front);
+        client.write_all(UPGRADE.as_bytes()).unwrap();
+
+        let head = read_response_head(&mut client);
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains(
+            "\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"
+        ));
+
+        let (first_byte, frame) = read_server_frame(&mut client);
+        assert_eq!(first_byte, 0x80 | OPCODE_BINARY);
+        assert_eq!(frame, encode_frame(&front.read()));
+        assert_eq!(&frame[..3], [1, 2, 3]);
+        assert_eq!(frame.len(), 8960 + TILE_WIDTH * TILE_HEIGHT);
+
+        // a masked close frame with an empty payload
+        client
+            .write_all(&[0x80 | OPCODE_CLOSE, 0x80, 1, 2, 3, 4])
+            .unwrap();
+        let (first_byte, _) = read_server_frame(&mut client);
+        assert_eq!(first_byte, 0x80 | OPCODE_CLOSE);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn serves_page() {
+        let (mut client, server) = connect(&front_buffer());
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n")
+            .unwrap();
+        server.join().unwrap().unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(PAGE));
+    }
+
+    #[test]
+    fn rejects_oversized_request() {
+        let (mut client, server) = connect(&front_buffer());
+        // all of it is read, unread bytes would reset the connection before the response arrives
+        let mut request = b"GET / HTTP/1.1\r\nX-Padding: ".to_vec();
+        request.resize(MAX_REQUEST_SIZE as usize, b'a');
+        client.write_all(&request).unwrap();
+        server.join().unwrap().unwrap();
+        let head = read_response_head(&mut client);
+        assert!(head.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn websocket_path_requires_upgrade() {
+        let (mut client, server) = connect(&front_buffer());
+        client
+            .write_all(b"GET /ws HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n")
+            .unwrap();
+        server.join().unwrap().unwrap();
+        let head = read_response_head(&mut client);
+        assert!(head.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
@@ -0,0 +1,340 @@
+//! The parts of the WebSocket protocol (RFC 6455) needed by the web viewer.
+
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Appended to the key of the client before hashing it for the handshake.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xA;
+
+#[derive(Debug)]
+pub enum Message {
+    Binary(Vec<u8>),
+    Text,
+    Ping(Vec<u8>),
+    Pong,
+    Close,
+}
+
+/// The value of the `Sec-WebSocket-Accept` header answering a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{key}{HANDSHAKE_GUID}").as_bytes()))
+}
+
+/// Writes a single unmasked frame, like a server has to.
+pub fn write_message(
+    stream: &mut impl Write,
+    opcode: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+/// Reads a message sent by a client, which has to fit into a single frame.
+///
+/// Browsers do not split the messages they send, so fragmented messages are rejected.
+pub fn read_message(
+    stream: &mut impl Read,
+    max_size: usize,
+) -> io::Result<Message> {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head)?;
+    let is_final = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    let is_masked = head[1] & 0x80 != 0;
+    let length = match head[1] & 0x7F {
+        126 => {
+            let mut length = [0; 2];
+            stream.read_exact(&mut length)?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0; 8];
+            stream.read_exact(&mut length)?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+
+    if !is_final || opcode == OPCODE_CONTINUATION {
+        return Err(invalid("fragmented messages are not supported"));
+    }
+    if !is_masked {
+        return Err(invalid("messages sent by clients have to be masked"));
+    }
+    if length > max_size as u64 {
+        return Err(invalid("message is too big"));
+    }
+
+    let mut mask = [0; 4];
+    stream.read_exact(&mut mask)?;
+    let mut payload = vec![0; length as usize];
+    stream.read_exact(&mut payload)?;
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+
+    match opcode {
+        OPCODE_BINARY => Ok(Message::Binary(payload)),
+        OPCODE_TEXT => Ok(Message::Text),
+        OPCODE_PING => Ok(Message::Ping(payload)),
+        OPCODE_PONG => Ok(Message::Pong),
+        OPCODE_CLOSE => Ok(Message::Close),
+        _ => Err(invalid("unknown opcode")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/// SHA-1 is broken as a cryptographic hash, but still required for the handshake.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] =
+        [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for index in 16..80 {
+            words[index] = (words[index - 3]
+                ^ words[index - 8]
+                ^ words[index - 14]
+                ^ words[index - 16])
+                .rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (index, word) in words.into_iter().enumerate() {
+            let (f, k) = match index {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, value) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = u32::from_be_bytes([
+            0,
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ]);
+        // a chunk of n bytes fills n + 1 characters, the rest is padding
+        for index in 0..4 {
+            if index <= chunk.len() {
+                let sextet = (bits >> (18 - 6 * index)) & 0x3F;
+                encoded.push(ALPHABET[sextet as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_SIZE: usize = 0x10000;
+
+    /// Frames a message like a browser, which has to mask it.
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = Vec::new();
+        write_message(&mut frame, opcode, payload).unwrap();
+        let header_length = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        let masked = payload
+            .iter()
+            .enumerate()
+            .map(|(index, byte)| byte ^ mask[index % 4]);
+        frame.splice(header_length.., mask.into_iter().chain(masked));
+        frame
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    #[test]
+    fn accept_key_of_rfc_example() {
+        // RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn sha1_test_vectors() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        // needs a second block for the padding
+        assert_eq!(
+            hex(&sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        assert_eq!(
+            hex(&sha1(&[b'a'; 1000])),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[test]
+    fn base64_test_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (data, encoded) in vectors {
+            assert_eq!(base64(data.as_bytes()), encoded);
+        }
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+    }
+
+    #[test]
+    fn write_message_lengths() {
+        for (length, header) in [
+            (0, vec![0x82, 0]),
+            (125, vec![0x82, 125]),
+            (126, vec![0x82, 126, 0, 126]),
+            (0xFFFF, vec![0x82, 126, 0xFF, 0xFF]),
+            (0x10000, vec![0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]),
+        ] {
+            let mut frame = Vec::new();
+            write_message(&mut frame, OPCODE_BINARY, &vec![7; length]).unwrap();
+            assert_eq!(frame[..header.len()], header, "length {length}");
+            assert_eq!(frame.len(), header.len() + length);
+        }
+    }
+
+    #[test]
+    fn read_masked_text_of_rfc_example() {
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let message = read_message(&mut frame.as_slice(), 100).unwrap();
+        assert!(matches!(message, Message::Text));
+    }
+
+    #[test]
+    fn read_messages() {
+        for length in [0, 5, 125, 126, 1000, 0xFFFF] {
+            let payload = (0..length).map(|i| i as u8).collect::<Vec<_>>();
+            let frame = client_frame(OPCODE_BINARY, &payload);
+            let message =
+                read_message(&mut frame.as_slice(), MAX_SIZE).unwrap();
+            assert!(
+                matches!(&message, Message::Binary(read) if *read == payload),
+                "length {length}"
+            );
+        }
+
+        let frame = client_frame(OPCODE_PING, b"ping");
+        let message = read_message(&mut frame.as_slice(), MAX_SIZE).unwrap();
+        assert!(matches!(&message, Message::Ping(read) if read == b"ping"));
+
+        let frame = client_frame(OPCODE_PONG, &[]);
+        let message = read_message(&mut frame.as_slice(), MAX_SIZE).unwrap();
+        assert!(matches!(message, Message::Pong));
+
+        let frame = client_frame(OPCODE_CLOSE, &[0x03, 0xe8]);
+        let message = read_message(&mut frame.as_slice(), MAX_SIZE).unwrap();
+        assert!(matches!(message, Message::Close));
+    }
+
+    #[test]
+    fn read_rejects_invalid_frames() {
+        let unmasked = [0x82, 1, 0];
+        let mut fragmented = client_frame(OPCODE_BINARY, b"part");
+        fragmented[0] &= 0x7F;
+        let continuation = client_frame(OPCODE_CONTINUATION, b"part");
+        let unknown_opcode = client_frame(0x3, b"");
+        let too_big = client_frame(OPCODE_BINARY, &[0; 11]);
+        for frame in [
+            &unmasked[..],
+            &fragmented,
+            &continuation,
+            &unknown_opcode,
+            &too_big,
+        ] {
+            let error = read_message(&mut &frame[..], 10).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{frame:?}");
+        }
+    }
+
+    #[test]
+    fn read_truncated_frame() {
+        let frame = client_frame(OPCODE_BINARY, b"payload");
+        let error =
+            read_message(&mut &frame[..frame.len() - 1], MAX_SIZE).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}